@@ -0,0 +1,165 @@
+use std::{
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use rand::rngs::OsRng;
+use rayon::ThreadPool;
+use snarkos_account::Account;
+use snarkvm::{
+    console::account::address::Address,
+    prelude::{CoinbasePuzzle, FromBytes, Network},
+    synthesizer::{Block, EpochChallenge},
+};
+use tokio::{
+    sync::mpsc,
+    task,
+    time::{sleep_until, Instant as TokioInstant},
+};
+use tracing::info;
+
+use crate::{
+    engine::{EngineEvent, Job, ProvingEngine},
+    nonce::{FleetSlot, NonceSpace},
+    prover,
+};
+
+/// Measures proving throughput, first with one blocking task per attempt the way the prover
+/// used to work, then with the proving engine, `duration` each on the same thread pools.
+///
+/// Proofs are made for a throwaway address with a proof target of zero, so every attempt runs
+/// the whole proof.
+pub async fn run<N: Network>(
+    threads: u16,
+    thread_pool_size: u8,
+    key_cache: Option<&Path>,
+    duration: Duration,
+) -> Result<()> {
+    let thread_pools = prover::thread_pools(threads, thread_pool_size, None, None)?;
+    let coinbase_puzzle = prover::load_coinbase_puzzle::<N>(key_cache)?;
+    let genesis = Block::<N>::from_bytes_le(N::genesis_bytes())?;
+    let epoch_challenge = EpochChallenge::new(1, genesis.hash(), N::COINBASE_PUZZLE_DEGREE)?;
+    let address = Account::<N>::new(&mut OsRng)?.address();
+
+    info!(
+        "Benchmarking one blocking task per attempt for {}s",
+        duration.as_secs()
+    );
+    let per_attempt = spawn_per_attempt(
+        &thread_pools,
+        &coinbase_puzzle,
+        &epoch_challenge,
+        address,
+        duration,
+    )
+    .await;
+    info!(
+        "Benchmarking the proving engine for {}s",
+        duration.as_secs()
+    );
+    let with_engine = engine(
+        &thread_pools,
+        &coinbase_puzzle,
+        &epoch_challenge,
+        address,
+        duration,
+    )
+    .await;
+
+    info!(
+        "{} pools: {:.2} proofs/s with a task per attempt, {:.2} proofs/s with the engine ({:+.1}%)",
+        thread_pools.len(),
+        per_attempt,
+        with_engine,
+        (with_engine / per_attempt - 1.0) * 100.0
+    );
+    Ok(())
+}
+
+/// The previous proving loop: every attempt clones its inputs, the proving key included, and
+/// goes through `spawn_blocking` and `install`.
+async fn spawn_per_attempt<N: Network>(
+    thread_pools: &[Arc<ThreadPool>],
+    coinbase_puzzle: &CoinbasePuzzle<N>,
+    epoch_challenge: &EpochChallenge<N>,
+    address: Address<N>,
+    duration: Duration,
+) -> f64 {
+    let proofs = Arc::new(AtomicU64::new(0));
+    let start = Instant::now();
+    let deadline = start + duration;
+    let mut tasks = Vec::new();
+    for tp in thread_pools {
+        let tp = tp.clone();
+        let coinbase_puzzle = coinbase_puzzle.clone();
+        let epoch_challenge = epoch_challenge.clone();
+        let proofs = proofs.clone();
+        tasks.push(task::spawn(async move {
+            let mut nonce = 0u64;
+            while Instant::now() < deadline {
+                let tp = tp.clone();
+                let coinbase_puzzle = coinbase_puzzle.clone();
+                let epoch_challenge = epoch_challenge.clone();
+                nonce += 1;
+                let _ = task::spawn_blocking(move || {
+                    tp.install(|| coinbase_puzzle.prove(&epoch_challenge, address, nonce, Some(0)))
+                })
+                .await;
+                proofs.fetch_add(1, Ordering::SeqCst);
+            }
+        }));
+    }
+    for task in tasks {
+        let _ = task.await;
+    }
+    // Attempts still running at the deadline were counted, so is the time they took.
+    proofs.load(Ordering::SeqCst) as f64 / start.elapsed().as_secs_f64()
+}
+
+async fn engine<N: Network>(
+    thread_pools: &[Arc<ThreadPool>],
+    coinbase_puzzle: &Arc<CoinbasePuzzle<N>>,
+    epoch_challenge: &EpochChallenge<N>,
+    address: Address<N>,
+    duration: Duration,
+) -> f64 {
+    let (sender, mut events) = mpsc::unbounded_channel();
+    let engine = ProvingEngine::start(
+        thread_pools,
+        coinbase_puzzle.clone(),
+        Default::default(),
+        sender,
+    );
+    let nonces = NonceSpace::new(0, FleetSlot::default());
+    let pools = thread_pools.len() as u32;
+    let start = Instant::now();
+    engine.set_job(Job {
+        epoch_number: epoch_challenge.epoch_number(),
+        epoch_challenge: epoch_challenge.clone(),
+        address,
+        nonces: (0..pools)
+            .map(|pool| Arc::new(nonces.range(epoch_challenge.epoch_number(), pool, pools)))
+            .collect(),
+        received: start,
+    });
+    let deadline = sleep_until(TokioInstant::from_std(start + duration));
+    tokio::pin!(deadline);
+    let mut proofs = 0u64;
+    loop {
+        tokio::select! {
+            _ = &mut deadline => break,
+            Some(event) = events.recv() => {
+                if let EngineEvent::Proved { .. } = event {
+                    proofs += 1;
+                }
+            }
+        }
+    }
+    // The loops can't be stopped, the process exits right after the benchmark.
+    proofs as f64 / start.elapsed().as_secs_f64()
+}
@@ -0,0 +1,329 @@
+//! A stand-in beacon for local end-to-end runs of the prover.
+//!
+//! Speaks just enough of the snarkOS node protocol for the prover: the
+//! `ChallengeRequest`/`ChallengeResponse`/`Ping`/`Pong` handshake with the real genesis header,
+//! `PuzzleResponse`s following a scripted epoch schedule, and every `UnconfirmedSolution`
+//! received is logged, echoed back and appended to a file.
+
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    net::SocketAddr,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Result};
+use clap::Parser;
+use futures_util::sink::SinkExt;
+use rand::{rngs::OsRng, Rng};
+use snarkos_account::Account;
+use snarkos_node_messages::{
+    ChallengeRequest, ChallengeResponse, Data, MessageCodec, NodeType, Pong, PuzzleResponse,
+};
+use snarkvm::{
+    prelude::{FromBytes, Network, Testnet3},
+    synthesizer::{Block, EpochChallenge, Header, Metadata},
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::watch,
+    task,
+    time::{sleep, Instant},
+};
+use tokio_stream::StreamExt;
+use tokio_util::codec::Framed;
+use tracing::{debug, error, info, warn};
+
+type Message = snarkos_node_messages::Message<Testnet3>;
+
+#[derive(Debug, Parser)]
+#[clap(
+    name = "mock-beacon",
+    about = "Local beacon for testing the prover offline"
+)]
+struct Opt {
+    /// Address to listen on
+    #[clap(short = 'l', long = "listen", default_value = "127.0.0.1:4133")]
+    listen: SocketAddr,
+
+    /// Schedule step "<epoch> <proof_target> <seconds>", can be repeated. Steps are served in
+    /// order, the last one forever. Defaults to epoch 1 at the genesis proof target
+    #[clap(long = "step")]
+    steps: Vec<Step>,
+
+    /// Append received solutions to this file, one "<unix ms> <epoch> <commitment>" per line
+    #[clap(long = "solutions")]
+    solutions: Option<String>,
+
+    /// Enable debug logging
+    #[clap(short = 'd', long = "debug")]
+    debug: bool,
+}
+
+/// One entry of the epoch schedule.
+#[derive(Clone, Copy, Debug)]
+struct Step {
+    epoch: u32,
+    proof_target: u64,
+    duration: Duration,
+}
+
+impl FromStr for Step {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let parts = s.split_whitespace().collect::<Vec<_>>();
+        if parts.len() != 3 {
+            return Err(format!(
+                "expected \"<epoch> <proof_target> <seconds>\", got {}",
+                s
+            ));
+        }
+        Ok(Self {
+            epoch: parts[0]
+                .parse()
+                .map_err(|_| format!("invalid epoch {}", parts[0]))?,
+            proof_target: parts[1]
+                .parse()
+                .map_err(|_| format!("invalid proof target {}", parts[1]))?,
+            duration: Duration::from_secs(
+                parts[2]
+                    .parse()
+                    .map_err(|_| format!("invalid duration {}", parts[2]))?,
+            ),
+        })
+    }
+}
+
+/// The work currently served to every connection.
+#[derive(Clone)]
+struct Work {
+    epoch_challenge: EpochChallenge<Testnet3>,
+    block_header: Header<Testnet3>,
+}
+
+struct MockBeacon {
+    account: Account<Testnet3>,
+    genesis: Block<Testnet3>,
+    work: watch::Receiver<Work>,
+    solutions: Option<Mutex<std::fs::File>>,
+}
+
+impl MockBeacon {
+    /// Builds the work for `step`: a challenge for its epoch and a header at the first height
+    /// of that epoch carrying its proof target.
+    fn work(&self, step: &Step) -> Result<Work> {
+        let genesis = self.genesis.header();
+        let height = step.epoch.max(1) * Testnet3::NUM_BLOCKS_PER_EPOCH;
+        let timestamp = genesis.timestamp() + height as i64 * Testnet3::ANCHOR_TIME as i64;
+        let metadata = Metadata::new(
+            Testnet3::ID,
+            height as u64,
+            height,
+            genesis.coinbase_target(),
+            step.proof_target,
+            genesis.coinbase_target(),
+            timestamp,
+            timestamp,
+        )
+        .map_err(|e| anyhow!("invalid step {:?}: {}", step, e))?;
+        let block_header = Header::from(
+            genesis.previous_state_root(),
+            genesis.transactions_root(),
+            genesis.coinbase_accumulator_point(),
+            metadata,
+        )?;
+        let epoch_challenge = EpochChallenge::new(
+            step.epoch,
+            self.genesis.hash(),
+            Testnet3::COINBASE_PUZZLE_DEGREE,
+        )?;
+        Ok(Work {
+            epoch_challenge,
+            block_header,
+        })
+    }
+
+    fn puzzle_response(&self) -> Message {
+        let work = self.work.borrow().clone();
+        Message::PuzzleResponse(PuzzleResponse {
+            epoch_challenge: work.epoch_challenge,
+            block_header: Data::Object(work.block_header),
+        })
+    }
+
+    fn record_solution(&self, epoch: u32, commitment: &str) {
+        if let Some(file) = &self.solutions {
+            let time = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis();
+            let mut file = file.lock().unwrap();
+            if let Err(e) = writeln!(file, "{} {} {}", time, epoch, commitment) {
+                warn!("Unable to record solution: {}", e);
+            }
+        }
+    }
+
+    async fn serve(&self, socket: TcpStream, peer: SocketAddr) -> Result<()> {
+        let mut framed = Framed::new(socket, MessageCodec::<Testnet3>::default());
+        let mut work = self.work.clone();
+        let our_nonce: u64 = OsRng.gen();
+        let mut peer_address = None;
+        let mut ready = false;
+        loop {
+            tokio::select! {
+                Ok(()) = work.changed(), if ready => {
+                    framed.send(self.puzzle_response()).await?;
+                    debug!("{} sent new work", peer);
+                }
+                message = framed.next() => {
+                    let message = match message {
+                        Some(message) => message?,
+                        None => return Ok(()),
+                    };
+                    debug!("{} sent {}", peer, message.name());
+                    match message {
+                        Message::ChallengeRequest(request) => {
+                            peer_address = Some(request.address);
+                            // Our challenge goes out before our response, like snarkOS sends them.
+                            framed
+                                .send(Message::ChallengeRequest(ChallengeRequest {
+                                    version: Message::VERSION,
+                                    listener_port: 4133,
+                                    node_type: NodeType::Beacon,
+                                    address: self.account.address(),
+                                    nonce: our_nonce,
+                                }))
+                                .await?;
+                            framed
+                                .send(Message::ChallengeResponse(ChallengeResponse {
+                                    genesis_header: *self.genesis.header(),
+                                    signature: Data::Object(
+                                        self.account.sign_bytes(&request.nonce.to_le_bytes(), &mut OsRng)?,
+                                    ),
+                                }))
+                                .await?;
+                        }
+                        Message::ChallengeResponse(response) => {
+                            let peer_address = peer_address
+                                .ok_or_else(|| anyhow!("challenge response before request"))?;
+                            if response.genesis_header != *self.genesis.header() {
+                                return Err(anyhow!("different genesis header"));
+                            }
+                            let signature = response.signature.deserialize().await?;
+                            if !signature.verify_bytes(&peer_address, &our_nonce.to_le_bytes()) {
+                                return Err(anyhow!("invalid challenge signature"));
+                            }
+                            info!("{} completed the handshake as {}", peer, peer_address);
+                            ready = true;
+                        }
+                        Message::Ping(_) => {
+                            framed.send(Message::Pong(Pong { is_fork: None })).await?;
+                        }
+                        Message::PuzzleRequest(_) => {
+                            framed.send(self.puzzle_response()).await?;
+                        }
+                        Message::UnconfirmedSolution(solution) => {
+                            let epoch = self.work.borrow().epoch_challenge.epoch_number();
+                            let commitment = solution.puzzle_commitment.to_string();
+                            info!("{} submitted solution {} in epoch {}", peer, commitment, epoch);
+                            self.record_solution(epoch, &commitment);
+                            // Gossip it back like a real beacon would.
+                            framed.send(Message::UnconfirmedSolution(solution)).await?;
+                        }
+                        Message::Disconnect(message) => {
+                            return Err(anyhow!("disconnected: {:?}", message.reason));
+                        }
+                        message => {
+                            debug!("{} sent unhandled {}", peer, message.name());
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let opt = Opt::parse();
+    tracing_subscriber::fmt()
+        .with_max_level(if opt.debug {
+            tracing::Level::DEBUG
+        } else {
+            tracing::Level::INFO
+        })
+        .init();
+
+    let genesis = Block::<Testnet3>::from_bytes_le(Testnet3::genesis_bytes())?;
+    let steps = match opt.steps.is_empty() {
+        true => vec![Step {
+            epoch: 1,
+            proof_target: genesis.header().proof_target(),
+            duration: Duration::MAX,
+        }],
+        false => opt.steps,
+    };
+    let solutions = match &opt.solutions {
+        Some(path) => Some(Mutex::new(
+            OpenOptions::new().create(true).append(true).open(path)?,
+        )),
+        None => None,
+    };
+
+    // The placeholder work is replaced by the first step before anything is served.
+    let placeholder = Work {
+        epoch_challenge: EpochChallenge::new(0, genesis.hash(), Testnet3::COINBASE_PUZZLE_DEGREE)?,
+        block_header: *genesis.header(),
+    };
+    let (work_sender, work) = watch::channel(placeholder);
+    let beacon = Arc::new(MockBeacon {
+        account: Account::new(&mut OsRng)?,
+        genesis,
+        work,
+        solutions,
+    });
+    let schedule = steps
+        .iter()
+        .map(|step| beacon.work(step))
+        .collect::<Result<Vec<_>>>()?;
+    work_sender.send_replace(schedule[0].clone());
+    info!(
+        "Serving epoch {} at proof target {}",
+        steps[0].epoch, steps[0].proof_target
+    );
+
+    task::spawn(async move {
+        let mut deadline = Instant::now();
+        for (previous, (step, work)) in steps.iter().zip(steps.iter().zip(schedule).skip(1)) {
+            deadline += previous.duration;
+            sleep(deadline.saturating_duration_since(Instant::now())).await;
+            info!(
+                "Serving epoch {} at proof target {}",
+                step.epoch, step.proof_target
+            );
+            work_sender.send_replace(work);
+        }
+    });
+
+    let listener = TcpListener::bind(opt.listen).await?;
+    info!(
+        "Mock beacon {} listening on {}",
+        beacon.account.address(),
+        opt.listen
+    );
+    loop {
+        let (socket, peer) = listener.accept().await?;
+        info!("{} connected", peer);
+        let beacon = beacon.clone();
+        task::spawn(async move {
+            match beacon.serve(socket, peer).await {
+                Ok(()) => info!("{} disconnected", peer),
+                Err(e) => error!("{} dropped: {}", peer, e),
+            }
+        });
+    }
+}
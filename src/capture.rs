@@ -0,0 +1,124 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Result};
+use bytes::{Buf, BytesMut};
+use snarkos_node_messages::MessageCodec;
+use snarkvm::prelude::Network;
+use tokio_util::codec::{Decoder, Encoder};
+use tracing::{info, warn};
+
+type Message<N> = snarkos_node_messages::Message<N>;
+
+/// First bytes of every capture file. Bump the last digit when the record layout changes.
+const MAGIC: &[u8; 8] = b"ALEOCAP1";
+
+/// Size of a record header: timestamp in microseconds, direction and connection index.
+const RECORD_HEADER_LEN: usize = 8 + 1 + 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+/// A message read back from a capture file.
+pub struct Captured<N: Network> {
+    /// Time since the Unix epoch when the message was seen.
+    pub time: Duration,
+    pub index: usize,
+    pub direction: Direction,
+    pub message: Message<N>,
+}
+
+/// Writes every beacon message to a capture file, see [`read`] for the layout.
+pub struct Recorder {
+    file: Mutex<BufWriter<File>>,
+}
+
+impl Recorder {
+    pub fn create(path: &Path) -> Result<Self> {
+        let mut file = BufWriter::new(
+            File::create(path)
+                .map_err(|e| anyhow!("Unable to create capture {}: {}", path.display(), e))?,
+        );
+        file.write_all(MAGIC)?;
+        file.flush()?;
+        info!("Recording beacon messages to {}", path.display());
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn record<N: Network>(&self, index: usize, direction: Direction, message: &Message<N>) {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(&time.to_le_bytes());
+        buf.extend_from_slice(&[
+            match direction {
+                Direction::Inbound => 0,
+                Direction::Outbound => 1,
+            },
+            index.min(u8::MAX as usize) as u8,
+        ]);
+        if let Err(e) = MessageCodec::<N>::default().encode(message.clone(), &mut buf) {
+            warn!("Unable to record {}: {}", message.name(), e);
+            return;
+        }
+        // Flush every record, a capture is most useful right after a crash.
+        let mut file = self.file.lock().unwrap();
+        if let Err(e) = file.write_all(&buf).and_then(|_| file.flush()) {
+            warn!("Unable to write capture: {}", e);
+        }
+    }
+}
+
+/// Reads a capture file.
+///
+/// The file starts with [`MAGIC`], followed by one record per message: the time in
+/// microseconds since the Unix epoch (u64 LE), the direction (0 inbound, 1 outbound), the
+/// connection index (u8) and the message framed exactly as it went over the wire.
+pub fn read<N: Network>(path: &Path) -> Result<Vec<Captured<N>>> {
+    let bytes = std::fs::read(path)
+        .map_err(|e| anyhow!("Unable to read capture {}: {}", path.display(), e))?;
+    if !bytes.starts_with(MAGIC) {
+        return Err(anyhow!("{} is not a capture file", path.display()));
+    }
+    let mut buf = BytesMut::from(&bytes[MAGIC.len()..]);
+    let mut codec = MessageCodec::<N>::default();
+    let mut records = Vec::new();
+    while buf.has_remaining() {
+        if buf.len() < RECORD_HEADER_LEN {
+            warn!("Ignoring truncated record at the end of {}", path.display());
+            break;
+        }
+        let time = Duration::from_micros(buf.get_u64_le());
+        let direction = match buf.get_u8() {
+            0 => Direction::Inbound,
+            1 => Direction::Outbound,
+            direction => return Err(anyhow!("Invalid direction {} in capture", direction)),
+        };
+        let index = buf.get_u8() as usize;
+        match codec.decode(&mut buf)? {
+            Some(message) => records.push(Captured {
+                time,
+                index,
+                direction,
+                message,
+            }),
+            None => {
+                warn!("Ignoring truncated record at the end of {}", path.display());
+                break;
+            }
+        }
+    }
+    Ok(records)
+}
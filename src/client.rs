@@ -1,87 +1,155 @@
 use std::{
-    sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
-    },
-    time::Duration,
+    marker::PhantomData,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use futures_util::sink::SinkExt;
+use futures::future::BoxFuture;
+use futures_util::sink::{Sink, SinkExt};
 
-use rand::{prelude::SliceRandom, rngs::OsRng, Rng};
-use snarkvm::{
-    console::account::address::Address,
-    prelude::{FromBytes, Network, Testnet3},
-    synthesizer::Block,
-};
+use rand::{rngs::OsRng, Rng};
 use snarkos_account::Account;
 use snarkos_node_messages::{
-    ChallengeRequest, ChallengeResponse, Data, MessageCodec, NodeType, Ping, Pong, PuzzleRequest,
-    PuzzleResponse,
+    ChallengeRequest, ChallengeResponse, Data, MessageCodec, NodeType, PeerRequest, PeerResponse,
+    Ping, Pong, PuzzleRequest, PuzzleResponse, UnconfirmedSolution,
+};
+use snarkvm::{
+    console::account::address::Address,
+    prelude::{FromBytes, Network},
+    synthesizer::{Block, EpochChallenge, Header, ProverSolution},
 };
 use tokio::{
-    net::TcpStream,
-    sync::{
-        mpsc,
-        mpsc::{Receiver, Sender},
-        Mutex,
-    },
     task,
-    time::{sleep, timeout},
+    time::{interval_at, sleep, sleep_until, timeout, Instant},
 };
 use tokio_stream::StreamExt;
 use tokio_util::codec::Framed;
 use tracing::{debug, error, info, warn};
 
-use crate::prover::{Prover, ProverEvent, Record};
+use crate::{
+    capture::{self, Direction, Recorder},
+    health::{BeaconHealth, Outcome},
+    outbound::{Outbound, OutboundQueue, SendCounters},
+    peers::PeerCache,
+    prover::{Prover, ProverEvent, Record},
+    proxy::Proxy,
+    resolve::{self, BeaconEntry},
+    rest::{self, NodeApi},
+    state::{ConnectionEvent, ConnectionMachine, ConnectionState},
+    tracker::SolutionTracker,
+};
+
+type Message<N> = snarkos_node_messages::Message<N>;
 
-type Message = snarkos_node_messages::Message<Testnet3>;
+const SEED_REFRESH_INTERVAL: Duration = Duration::from_secs(10 * 60);
 
-pub struct Client {
-    pub address: Address<Testnet3>,
-    pub servers: Vec<String>,
-    sender: Arc<Sender<Message>>,
-    receiver: Arc<Mutex<Receiver<Message>>>,
+/// How often discovered peers are probed.
+const DISCOVERY_INTERVAL: Duration = Duration::from_secs(60);
+/// A discovered peer is probed again at most this often.
+const PROBE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// Peers probed per round at most.
+const MAX_PROBES: usize = 8;
+/// Discovered peers waiting for a probe at most.
+const MAX_DISCOVERED: usize = 1024;
+
+pub struct Client<N: Network> {
+    pub address: Address<N>,
+    network: &'static str,
+    health: BeaconHealth,
+    outbound: Arc<OutboundQueue<N>>,
+    sent: Arc<SendCounters>,
     worker: String,
+    proxy: Option<Proxy>,
 }
 
-impl Client {
-    pub fn init(address: Address<Testnet3>, servers: Vec<String>, worker: String) -> Arc<Self> {
-        let (sender, receiver) = mpsc::channel(1024);
+impl<N: Network> Client<N> {
+    pub fn init(
+        address: Address<N>,
+        network: &'static str,
+        beacons: Vec<BeaconEntry>,
+        worker: String,
+        proxy: Option<Proxy>,
+    ) -> Arc<Self> {
         Arc::new(Self {
             address,
-            servers,
-            sender: Arc::new(sender),
-            receiver: Arc::new(Mutex::new(receiver)),
+            network,
+            health: BeaconHealth::new(&beacons),
+            outbound: Default::default(),
+            sent: Default::default(),
             worker,
+            proxy,
         })
     }
 
-
-    pub fn address(&self) -> Address<Testnet3> {
+    pub fn address(&self) -> Address<N> {
         self.address.clone()
     }
 
+    /// Name of the network in node REST API paths, e.g. `testnet3`.
+    pub fn network(&self) -> &'static str {
+        self.network
+    }
+
     pub fn get_worker(&self) -> String {
         self.worker.clone()
     }
-    pub fn sender(&self) -> Arc<Sender<Message>> {
-        self.sender.clone()
+
+    pub fn proxy(&self) -> Option<&Proxy> {
+        self.proxy.as_ref()
+    }
+
+    pub fn health(&self) -> &BeaconHealth {
+        &self.health
+    }
+
+    pub fn outbound(&self) -> Arc<OutboundQueue<N>> {
+        self.outbound.clone()
+    }
+
+    pub fn sent(&self) -> Arc<SendCounters> {
+        self.sent.clone()
     }
 
-    pub fn receiver(&self) -> Arc<Mutex<Receiver<Message>>> {
-        self.receiver.clone()
+    /// Queues a solution found for `epoch` for submission through the active work source.
+    pub fn submit(&self, epoch: u32, solution: ProverSolution<N>) {
+        let message = Message::UnconfirmedSolution(UnconfirmedSolution {
+            puzzle_commitment: solution.commitment(),
+            solution: Data::Object(solution),
+        });
+        self.outbound.push(Outbound {
+            message,
+            epoch: Some(epoch),
+        });
     }
 }
 
-pub fn report(prover: Arc<Prover>, client: Arc<Client>) {
+pub fn report<N: Network>(prover: Arc<Prover<N>>, client: Arc<Client<N>>) {
     let receiver = prover.record_receiver();
     task::spawn(async move {
+        let mut builder = reqwest::Client::builder();
+        if let Some(proxy) = client.proxy() {
+            match proxy.to_reqwest() {
+                Ok(proxy) => builder = builder.proxy(proxy),
+                Err(e) => {
+                    // Never report around the proxy.
+                    error!("Invalid proxy for reporting, not reporting: {}", e);
+                    return;
+                }
+            }
+        }
+        let http_client = match builder.build() {
+            Ok(http_client) => http_client,
+            Err(e) => {
+                error!("Unable to set up reporting: {}", e);
+                return;
+            }
+        };
         let receiver = &mut *receiver.lock().await;
         loop {
             tokio::select! {
                 Some(message) = receiver.recv() => {
-                    let http_client = reqwest::Client::new();
                     let resp = http_client
                         .post(r#"https://record.aleopro.com/record"#)
                         .json(&Record {
@@ -109,202 +177,1106 @@ pub fn report(prover: Arc<Prover>, client: Arc<Client>) {
     });
 }
 
-pub fn start(prover: Arc<Prover>, client: Arc<Client>) {
-    task::spawn(async move {
-        let receiver = client.receiver();
-        let genesis_header = *Block::<Testnet3>::from_bytes_le(Testnet3::genesis_bytes())
-            .unwrap()
-            .header();
-        let connected = Arc::new(AtomicBool::new(false));
-        let client_sender = client.sender();
+/// A source of proving work.
+///
+/// The prover only ever talks to the outbound queue of [`Client`]. A work source owns the
+/// connection on the other side: it turns queued messages into wire traffic and feeds
+/// [`ProverEvent`]s back into the prover.
+pub trait WorkSource<N: Network>: Send + Sync {
+    fn name(&self) -> &'static str;
 
-        let connected_req = connected.clone();
-        task::spawn(async move {
+    fn run(
+        self: Arc<Self>,
+        prover: Arc<Prover<N>>,
+        client: Arc<Client<N>>,
+    ) -> BoxFuture<'static, ()>;
+}
+
+pub fn start<N: Network>(
+    prover: Arc<Prover<N>>,
+    client: Arc<Client<N>>,
+    source: Arc<dyn WorkSource<N>>,
+) {
+    info!("Starting {} work source", source.name());
+    task::spawn(source.run(prover, client));
+}
+
+#[derive(Clone)]
+pub struct BeaconConfig<N: Network> {
+    /// Number of beacon sessions to keep open at once.
+    pub connections: usize,
+    /// Time allowed from TCP connect until the first `Pong`.
+    pub handshake_timeout: Duration,
+    /// Longest silence from the beacon before the session is considered dead.
+    pub idle_timeout: Duration,
+    /// Interval between our own `Ping`s once the session is ready.
+    pub ping_interval: Duration,
+    /// If not empty, only beacons signing with one of these addresses are accepted.
+    pub beacon_addresses: Vec<Address<N>>,
+    /// Key to identify ourselves to beacons with, a random one is used if not set.
+    pub identity: Option<Account<N>>,
+    /// Seed list file or URL, reloaded periodically to pick up new beacons.
+    pub seeds: Option<String>,
+    /// Base URL of a snarkOS node REST API, used to look for our solutions in blocks and to
+    /// submit solutions while no beacon connection is ready.
+    pub node_api: Option<String>,
+    /// Capture every message sent to or received from beacons.
+    pub recorder: Option<Arc<Recorder>>,
+    /// File to keep discovered peers in across restarts.
+    pub peer_cache: Option<PathBuf>,
+}
+
+impl<N: Network> Default for BeaconConfig<N> {
+    fn default() -> Self {
+        Self {
+            connections: 1,
+            handshake_timeout: Duration::from_secs(10),
+            idle_timeout: Duration::from_secs(60),
+            ping_interval: Duration::from_secs(15),
+            beacon_addresses: Vec::new(),
+            identity: None,
+            seeds: None,
+            node_api: None,
+            recorder: None,
+            peer_cache: None,
+        }
+    }
+}
+
+/// Direct connection to beacons speaking the snarkOS node protocol.
+///
+/// Keeps `connections` sessions open at once, each to a different beacon when possible.
+/// Outbound messages go to every ready session, and work is forwarded to the prover only once
+/// per epoch no matter how many beacons announce it.
+pub struct BeaconSource<N: Network> {
+    config: BeaconConfig<N>,
+}
+
+impl<N: Network> BeaconSource<N> {
+    pub fn new(mut config: BeaconConfig<N>) -> Self {
+        config.connections = config.connections.max(1);
+        Self { config }
+    }
+}
+
+impl<N: Network> WorkSource<N> for BeaconSource<N> {
+    fn name(&self) -> &'static str {
+        "beacon"
+    }
+
+    fn run(
+        self: Arc<Self>,
+        prover: Arc<Prover<N>>,
+        client: Arc<Client<N>>,
+    ) -> BoxFuture<'static, ()> {
+        Box::pin(async move {
+            let beacons = match Beacons::new(prover, client, self.config.clone()) {
+                Ok(beacons) => Arc::new(beacons),
+                Err(e) => {
+                    error!("Unable to start the beacon source: {}", e);
+                    return;
+                }
+            };
+            info!(
+                "Keeping {} beacon connection(s) open",
+                self.config.connections
+            );
+
+            let beacons_req = beacons.clone();
+            let states = beacons
+                .machines
+                .iter()
+                .map(|machine| machine.subscribe())
+                .collect::<Vec<_>>();
+            let outbound = beacons.client.outbound();
+            task::spawn(async move {
+                loop {
+                    sleep(Duration::from_secs(N::ANCHOR_TIME as u64)).await;
+                    beacons_req.log_sessions();
+                    if states
+                        .iter()
+                        .any(|state| *state.borrow() == ConnectionState::Ready)
+                    {
+                        outbound.push(Message::PuzzleRequest(PuzzleRequest {}).into());
+                    }
+                }
+            });
+            info!("Created coinbase puzzle request task");
+
+            let beacons_discovery = beacons.clone();
+            task::spawn(async move {
+                loop {
+                    sleep(DISCOVERY_INTERVAL).await;
+                    beacons_discovery.discover().await;
+                }
+            });
+
+            if let Some(seeds) = self.config.seeds.clone() {
+                let client = beacons.client.clone();
+                task::spawn(async move {
+                    loop {
+                        sleep(SEED_REFRESH_INTERVAL).await;
+                        match resolve::load_seeds(&seeds, client.proxy()).await {
+                            Ok(beacons) => client.health().add(&beacons),
+                            Err(e) => warn!("Failed to reload seeds from {}: {}", seeds, e),
+                        }
+                    }
+                });
+            }
+
+            for index in 0..self.config.connections {
+                let beacons = beacons.clone();
+                task::spawn(async move {
+                    loop {
+                        beacons.connect(index).await;
+                        sleep(Duration::from_secs(5)).await;
+                    }
+                });
+            }
+
+            // Follow the node API while no beacon is ready, so solutions submitted to it are
+            // made for the node's current epoch.
+            if let Some(api) = beacons.rest.clone() {
+                let beacons = beacons.clone();
+                task::spawn(async move {
+                    // The node API has no session, it logs as the one after the last.
+                    let index = beacons.machines.len();
+                    loop {
+                        sleep(rest::POLL_INTERVAL).await;
+                        if beacons
+                            .machines
+                            .iter()
+                            .any(|machine| machine.state() == ConnectionState::Ready)
+                        {
+                            continue;
+                        }
+                        match api.latest_work().await {
+                            Ok((epoch_challenge, block)) => {
+                                if let Err(e) = beacons
+                                    .new_work(index, epoch_challenge, *block.header())
+                                    .await
+                                {
+                                    warn!("{} sent bogus work: {}", api.base(), e);
+                                }
+                            }
+                            Err(e) => warn!("Failed to fetch work from {}: {}", api.base(), e),
+                        }
+                    }
+                });
+            }
+
+            // Fan the outbound queue out to every ready session.
+            let outbound = beacons.client.outbound();
             loop {
-                sleep(Duration::from_secs(Testnet3::ANCHOR_TIME as u64)).await;
-                if connected_req.load(Ordering::SeqCst) {
-                    if let Err(e) = client_sender
-                        .send(Message::PuzzleRequest(PuzzleRequest {}))
-                        .await
+                let Outbound { message, epoch } = outbound.pop().await;
+                if let Some(epoch) = epoch {
+                    let latest = beacons.latest_work.lock().unwrap().epoch;
+                    if epoch != latest {
+                        info!(
+                            "Dropping stale solution for epoch {}, current epoch is {}",
+                            epoch, latest
+                        );
+                        continue;
+                    }
+                }
+                let queues = beacons
+                    .sessions
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .zip(beacons.machines.iter())
+                    .filter(|(_, machine)| machine.state() == ConnectionState::Ready)
+                    .filter_map(|(session, _)| session.queue.clone())
+                    .collect::<Vec<_>>();
+                if queues.is_empty() {
+                    match (epoch, &beacons.rest) {
+                        (Some(epoch), Some(rest)) => {
+                            if let Message::UnconfirmedSolution(solution) = &message {
+                                beacons.track(solution, epoch);
+                            }
+                            let rest = rest.clone();
+                            task::spawn(async move {
+                                match rest.submit_message(message).await {
+                                    Ok(()) => info!(
+                                        "No beacon connection is ready, submitted solution to {}",
+                                        rest.base()
+                                    ),
+                                    Err(e) => {
+                                        warn!("Failed to submit solution to {}: {}", rest.base(), e)
+                                    }
+                                }
+                            });
+                        }
+                        (Some(epoch), None) => {
+                            warn!(
+                                "No beacon connection is ready, holding solution for epoch {}",
+                                epoch
+                            );
+                            beacons.held.lock().unwrap().push((epoch, message));
+                        }
+                        (None, _) => {
+                            warn!("No beacon connection is ready, dropping {}", message.name());
+                        }
+                    }
+                    continue;
+                }
+                if let (Message::UnconfirmedSolution(solution), Some(epoch)) = (&message, epoch) {
+                    beacons.track(solution, epoch);
+                }
+                for queue in queues {
+                    queue.push(Outbound {
+                        message: message.clone(),
+                        epoch,
+                    });
+                }
+            }
+        })
+    }
+}
+
+/// Feeds a capture file made with `--record` back through the beacon pipeline, with no
+/// network. Inbound messages are replayed with their original spacing divided by `speed`,
+/// outbound ones are only logged.
+pub struct ReplaySource<N: Network> {
+    path: PathBuf,
+    speed: f64,
+    _network: PhantomData<N>,
+}
+
+impl<N: Network> ReplaySource<N> {
+    /// A `speed` of 0 replays without any delay.
+    pub fn new(path: PathBuf, speed: f64) -> Self {
+        Self {
+            path,
+            speed,
+            _network: PhantomData,
+        }
+    }
+}
+
+impl<N: Network> WorkSource<N> for ReplaySource<N> {
+    fn name(&self) -> &'static str {
+        "replay"
+    }
+
+    fn run(
+        self: Arc<Self>,
+        prover: Arc<Prover<N>>,
+        client: Arc<Client<N>>,
+    ) -> BoxFuture<'static, ()> {
+        Box::pin(async move {
+            let records = match capture::read::<N>(&self.path) {
+                Ok(records) => records,
+                Err(e) => {
+                    error!("Unable to replay: {}", e);
+                    return;
+                }
+            };
+            info!(
+                "Replaying {} messages from {}",
+                records.len(),
+                self.path.display()
+            );
+            let beacons = match Beacons::new(prover, client, BeaconConfig::default()) {
+                Ok(beacons) => Arc::new(beacons),
+                Err(e) => {
+                    error!("Unable to replay: {}", e);
+                    return;
+                }
+            };
+
+            // Nothing leaves during a replay, but solutions are tracked as if they did.
+            let drain = beacons.clone();
+            task::spawn(async move {
+                let outbound = drain.client.outbound();
+                loop {
+                    let Outbound { message, epoch } = outbound.pop().await;
+                    if let (Message::UnconfirmedSolution(solution), Some(epoch)) = (&message, epoch)
                     {
-                        error!("Failed to send puzzle request: {}", e);
+                        drain.track(solution, epoch);
+                    }
+                    info!("Replay: not sending {}", message.name());
+                }
+            });
+
+            let mut previous = None;
+            for record in records {
+                if let Some(previous) = previous {
+                    if self.speed > 0.0 {
+                        let gap: Duration = record.time.saturating_sub(previous);
+                        sleep(gap.div_f64(self.speed)).await;
+                    }
+                }
+                previous = Some(record.time);
+                let index = record.index;
+                match record.direction {
+                    Direction::Outbound => {
+                        debug!(
+                            "#{} recorded {} sent to beacon",
+                            index,
+                            record.message.name()
+                        );
+                    }
+                    Direction::Inbound => {
+                        debug!("#{} replaying {} from beacon", index, record.message.name());
+                        beacons.replay(index, record.message).await;
+                    }
+                }
+            }
+            info!("Replay of {} finished", self.path.display());
+        })
+    }
+}
+
+/// Blocks may come faster than the anchor time for a while, but not this much faster.
+const MAX_BLOCK_RATE: i64 = 4;
+
+/// Seconds a block timestamp may be ahead of our clock.
+const MAX_CLOCK_SKEW: i64 = 10 * 60;
+
+/// Checks that a puzzle response is consistent before its epoch is believed: the epoch has to
+/// match the block height, the height has to be reachable since genesis at the anchor time and
+/// the proof target has to be below the coinbase target.
+pub fn check_work<N: Network>(
+    genesis: &Header<N>,
+    epoch_challenge: &EpochChallenge<N>,
+    header: &Header<N>,
+) -> Result<(), String> {
+    let epoch = epoch_challenge.epoch_number();
+    let height = header.height();
+    if epoch.abs_diff(height / N::NUM_BLOCKS_PER_EPOCH) > 1 {
+        return Err(format!("epoch {} does not match height {}", epoch, height));
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+    if header.timestamp() > now + MAX_CLOCK_SKEW {
+        return Err(format!("block {} is from the future", height));
+    }
+    let elapsed = (now - genesis.timestamp()).max(0);
+    let max_height =
+        elapsed / N::ANCHOR_TIME as i64 * MAX_BLOCK_RATE + N::NUM_BLOCKS_PER_EPOCH as i64;
+    if height as i64 > max_height {
+        return Err(format!(
+            "height {} is out of reach {}s after genesis",
+            height, elapsed
+        ));
+    }
+    let proof_target = header.proof_target();
+    if proof_target == 0 || proof_target > header.coinbase_target() {
+        return Err(format!("invalid proof target {}", proof_target));
+    }
+    Ok(())
+}
+
+/// Work last forwarded to the prover.
+#[derive(Default)]
+struct LatestWork {
+    epoch: u32,
+    proof_target: u64,
+    height: u32,
+}
+
+/// Per-connection bookkeeping, indexed by connection number.
+struct Session<N: Network> {
+    server: Option<String>,
+    queue: Option<Arc<OutboundQueue<N>>>,
+    /// Latest epoch announced on this connection.
+    epoch: Option<u32>,
+}
+
+impl<N: Network> Default for Session<N> {
+    fn default() -> Self {
+        Self {
+            server: None,
+            queue: None,
+            epoch: None,
+        }
+    }
+}
+
+struct Beacons<N: Network> {
+    prover: Arc<Prover<N>>,
+    client: Arc<Client<N>>,
+    genesis_header: Header<N>,
+    account: Account<N>,
+    sessions: std::sync::Mutex<Vec<Session<N>>>,
+    machines: Vec<ConnectionMachine>,
+    config: BeaconConfig<N>,
+    latest_work: std::sync::Mutex<LatestWork>,
+    tracker: Arc<SolutionTracker<N>>,
+    /// Solutions found while no connection was ready, with their epoch.
+    held: std::sync::Mutex<Vec<(u32, Message<N>)>>,
+    peers: PeerCache,
+    /// Fallback for work and solutions while no connection is ready.
+    rest: Option<Arc<NodeApi<N>>>,
+    /// Addresses from `PeerResponse`s waiting to be probed.
+    discovered: std::sync::Mutex<Vec<SocketAddr>>,
+}
+
+impl<N: Network> Beacons<N> {
+    fn new(
+        prover: Arc<Prover<N>>,
+        client: Arc<Client<N>>,
+        config: BeaconConfig<N>,
+    ) -> anyhow::Result<Self> {
+        let genesis_header = *Block::<N>::from_bytes_le(N::genesis_bytes())
+            .unwrap()
+            .header();
+        let tracker =
+            SolutionTracker::new(config.node_api.clone(), client.network(), client.proxy())?;
+        let rest = config.node_api.as_ref().and_then(|api| {
+            match NodeApi::new(api, client.network(), client.proxy()) {
+                Ok(rest) => Some(Arc::new(rest)),
+                Err(e) => {
+                    warn!("Invalid node API {}: {}", api, e);
+                    None
+                }
+            }
+        });
+        let peers = PeerCache::load(config.peer_cache.clone());
+        let known = peers
+            .beacons()
+            .into_iter()
+            .filter(|peer| !client.health().contains(&peer.server))
+            .collect::<Vec<_>>();
+        client.health().add(&known);
+        Ok(Self {
+            prover,
+            client,
+            genesis_header,
+            account: match config.identity.clone() {
+                Some(account) => account,
+                None => Account::new(&mut OsRng).unwrap(),
+            },
+            sessions: std::sync::Mutex::new(
+                (0..config.connections)
+                    .map(|_| Session::default())
+                    .collect(),
+            ),
+            machines: (0..config.connections)
+                .map(ConnectionMachine::new)
+                .collect(),
+            config,
+            latest_work: Default::default(),
+            tracker: Arc::new(tracker),
+            held: Default::default(),
+            peers,
+            rest,
+            discovered: Default::default(),
+        })
+    }
+
+    fn log_sessions(&self) {
+        let sessions = self.sessions.lock().unwrap();
+        let states = sessions
+            .iter()
+            .zip(self.machines.iter())
+            .enumerate()
+            .map(|(index, (session, machine))| {
+                format!(
+                    "#{} {} {}",
+                    index,
+                    session.server.as_deref().unwrap_or("-"),
+                    machine.state()
+                )
+            })
+            .collect::<Vec<_>>();
+        info!("Beacon connections: {}", states.join(", "));
+        let sent = self
+            .client
+            .sent()
+            .snapshot()
+            .into_iter()
+            .map(|(name, count)| format!("{} {}", name, count))
+            .collect::<Vec<_>>();
+        debug!("Sent to beacons: {}", sent.join(", "));
+    }
+
+    /// Writes `message` to a beacon connection, counting and recording it.
+    async fn send<S>(
+        &self,
+        index: usize,
+        framed: &mut S,
+        message: Message<N>,
+    ) -> std::io::Result<()>
+    where
+        S: Sink<Message<N>, Error = std::io::Error> + Unpin,
+    {
+        if let Some(recorder) = &self.config.recorder {
+            recorder.record(index, Direction::Outbound, &message);
+        }
+        let name = message.name().to_string();
+        framed.send(message).await?;
+        self.client.sent().record(&name);
+        Ok(())
+    }
+
+    fn track(&self, solution: &UnconfirmedSolution<N>, epoch: u32) {
+        let height = self.latest_work.lock().unwrap().height;
+        self.tracker
+            .track(solution.puzzle_commitment, epoch, height);
+    }
+
+    /// Takes the held solutions that are still good for the current epoch.
+    fn take_held(&self) -> Vec<Message<N>> {
+        let epoch = self.latest_work.lock().unwrap().epoch;
+        let held = std::mem::take(&mut *self.held.lock().unwrap());
+        held.into_iter()
+            .filter(|(held_epoch, _)| *held_epoch == epoch)
+            .map(|(held_epoch, message)| {
+                if let Message::UnconfirmedSolution(solution) = &message {
+                    self.track(solution, held_epoch);
+                }
+                message
+            })
+            .collect()
+    }
+
+    /// Handles a recorded inbound message the way a live session would have.
+    async fn replay(&self, index: usize, message: Message<N>) {
+        match message {
+            Message::PuzzleResponse(PuzzleResponse {
+                epoch_challenge,
+                block_header,
+            }) => match block_header.deserialize().await {
+                Ok(block_header) => {
+                    if let Err(e) = self.new_work(index, epoch_challenge, block_header).await {
+                        warn!("#{} refused work: {}", index, e);
+                    }
+                }
+                Err(e) => error!("#{} error deserializing block header: {:?}", index, e),
+            },
+            Message::UnconfirmedSolution(message) => {
+                if self.tracker.confirm(&message.puzzle_commitment) {
+                    let result = ProverEvent::Result(true, Some("echoed in replay".to_string()));
+                    if let Err(e) = self.prover.sender().send(result).await {
+                        error!("#{} error sending share result to prover: {}", index, e);
                     }
                 }
             }
+            Message::Disconnect(message) => {
+                info!("#{} peer disconnected: {:?}", index, message.reason);
+            }
+            message => {
+                debug!("#{} nothing to replay for {}", index, message.name());
+            }
+        }
+    }
+
+    /// Forwards a puzzle response to the prover unless another connection already did.
+    ///
+    /// Work that fails [`check_work`] is refused. An epoch below the one the prover is on is
+    /// only followed once no live connection reports the higher one anymore, which is what
+    /// happens after failing over to a beacon that is a little behind.
+    async fn new_work(
+        &self,
+        index: usize,
+        epoch_challenge: EpochChallenge<N>,
+        block_header: Header<N>,
+    ) -> Result<(), String> {
+        check_work(&self.genesis_header, &epoch_challenge, &block_header)?;
+        let epoch_number = epoch_challenge.epoch_number();
+        let proof_target = block_header.proof_target();
+        let highest = {
+            let mut sessions = self.sessions.lock().unwrap();
+            if let Some(session) = sessions.get_mut(index) {
+                session.epoch = Some(epoch_number);
+            }
+            sessions
+                .iter()
+                .filter_map(|session| session.epoch)
+                .max()
+                .unwrap_or(epoch_number)
+        };
+        if epoch_number < highest {
+            debug!(
+                "#{} is behind at epoch {}, another beacon is at {}",
+                index, epoch_number, highest
+            );
+            return Ok(());
+        }
+        let (new_epoch, new_target, height, previous) = {
+            let mut latest = self.latest_work.lock().unwrap();
+            let previous = latest.epoch;
+            let new_epoch = epoch_number != latest.epoch || latest.proof_target == 0;
+            let new_target = new_epoch || proof_target != latest.proof_target;
+            if new_target {
+                latest.epoch = epoch_number;
+                latest.proof_target = proof_target;
+            }
+            latest.height = match epoch_number < previous {
+                true => block_header.height(),
+                false => latest.height.max(block_header.height()),
+            };
+            (new_epoch, new_target, latest.height, previous)
+        };
+        if new_target {
+            if let Err(e) = self
+                .prover
+                .sender()
+                .send(ProverEvent::NewTarget(proof_target))
+                .await
+            {
+                error!("Error sending new target to prover: {}", e);
+            } else {
+                debug!("Sent new target to prover");
+            }
+        }
+        if !new_epoch {
+            debug!("#{} epoch {} was already announced", index, epoch_number);
+            return Ok(());
+        }
+        if epoch_number < previous {
+            warn!(
+                "#{} epoch went back from {} to {} at height {}, following it",
+                index,
+                previous,
+                epoch_number,
+                block_header.height()
+            );
+        }
+        info!("#{} announced epoch {}", index, epoch_number);
+        {
+            let mut held = self.held.lock().unwrap();
+            let before = held.len();
+            held.retain(|(held_epoch, _)| *held_epoch == epoch_number);
+            if held.len() < before {
+                info!("Dropped {} held stale solution(s)", before - held.len());
+            }
+        }
+        for session in self.sessions.lock().unwrap().iter() {
+            if let Some(queue) = &session.queue {
+                let dropped = queue.retain_epoch(epoch_number);
+                if dropped > 0 {
+                    info!("Dropped {} queued stale solution(s)", dropped);
+                }
+            }
+        }
+        let tracker = self.tracker.clone();
+        let prover = self.prover.sender();
+        task::spawn(async move {
+            tracker.epoch_changed(epoch_number, height, &prover).await;
         });
+        if let Err(e) = self
+            .prover
+            .sender()
+            .send(ProverEvent::NewWork(
+                epoch_number,
+                epoch_challenge,
+                self.client.address(),
+            ))
+            .await
+        {
+            error!("Error sending new work to prover: {}", e);
+        } else {
+            debug!("Sent new work to prover");
+        }
+        Ok(())
+    }
 
-        info!("Created coinbase puzzle request task");
+    fn challenge_request(&self, nonce: u64) -> Message<N> {
+        Message::ChallengeRequest(ChallengeRequest {
+            version: Message::<N>::VERSION,
+            listener_port: 4140,
+            node_type: NodeType::Prover,
+            address: self.account.address(),
+            nonce,
+        })
+    }
 
-        let rng = &mut OsRng;
+    /// Checks the `ChallengeRequest` of a peer before we answer it.
+    fn check_peer(
+        &self,
+        version: u32,
+        node_type: NodeType,
+        address: &Address<N>,
+    ) -> Result<(), String> {
+        if version < Message::<N>::VERSION {
+            return Err("peer is running an older version of the protocol".to_string());
+        }
+        if node_type != NodeType::Beacon && node_type != NodeType::Validator {
+            return Err(format!(
+                "peer is a {:?}, not a beacon or validator",
+                node_type
+            ));
+        }
+        if !self.config.beacon_addresses.is_empty()
+            && !self.config.beacon_addresses.contains(address)
+        {
+            return Err(format!(
+                "peer address {} is not in the beacon allowlist",
+                address
+            ));
+        }
+        Ok(())
+    }
 
-        let random_account = Account::new(rng).unwrap();
+    /// Probes some of the discovered peers and adds the ones that turn out to be beacons or
+    /// validators to the candidates, then saves the peer cache.
+    async fn discover(&self) {
+        let candidates = {
+            let mut discovered = self.discovered.lock().unwrap();
+            std::mem::take(&mut *discovered)
+        };
+        let candidates = candidates
+            .into_iter()
+            .map(|peer| peer.to_string())
+            .filter(|server| !self.client.health().contains(server))
+            .filter(|server| !self.peers.tried_within(server, PROBE_INTERVAL))
+            .take(MAX_PROBES)
+            .collect::<Vec<_>>();
+        if !candidates.is_empty() {
+            debug!("Probing {} discovered peers", candidates.len());
+        }
+        for server in candidates {
+            match self.probe(&server).await {
+                Ok(()) => {
+                    self.peers.record(&server, true);
+                    self.client
+                        .health()
+                        .add(&[BeaconEntry { server, weight: 1 }]);
+                }
+                Err(e) => {
+                    debug!("Discovered peer {} is no use: {}", server, e);
+                    self.peers.record(&server, false);
+                }
+            }
+        }
+        self.peers.save();
+    }
+
+    /// Connects to `server` and waits for its `ChallengeRequest`, without finishing the
+    /// handshake.
+    async fn probe(&self, server: &str) -> Result<(), String> {
+        let socket = resolve::connect(self.client.proxy(), server, Duration::from_secs(5))
+            .await
+            .map_err(|e| e.to_string())?;
+        let mut framed = Framed::new(socket, MessageCodec::<N>::default());
+        framed
+            .send(self.challenge_request(OsRng.gen()))
+            .await
+            .map_err(|e| e.to_string())?;
+        let request = timeout(self.config.handshake_timeout, async {
+            while let Some(message) = framed.next().await {
+                if let Message::ChallengeRequest(request) = message.map_err(|e| e.to_string())? {
+                    return Ok(request);
+                }
+            }
+            Err("connection closed".to_string())
+        })
+        .await
+        .map_err(|_| "handshake timed out".to_string())??;
+        self.check_peer(request.version, request.node_type, &request.address)
+    }
 
+    /// Picks a beacon for connection `index` and runs one session against it.
+    async fn connect(&self, index: usize) {
+        let server = {
+            let sessions = self.sessions.lock().unwrap();
+            let in_use = sessions
+                .iter()
+                .enumerate()
+                .filter(|(other, _)| *other != index)
+                .filter_map(|(_, session)| session.server.clone())
+                .collect::<Vec<_>>();
+            drop(sessions);
+            self.client.health().pick(&in_use)
+        };
+        if let Some(ban) = self.client.health().banned_for(&server) {
+            warn!(
+                "#{} all beacons are banned, waiting {}s for {}",
+                index,
+                ban.as_secs(),
+                server
+            );
+            sleep(ban).await;
+        }
+        let queue = Arc::new(OutboundQueue::default());
+        {
+            let mut sessions = self.sessions.lock().unwrap();
+            sessions[index] = Session {
+                server: Some(server.clone()),
+                queue: Some(queue.clone()),
+                epoch: None,
+            };
+        }
+        let machine = &self.machines[index];
+        machine.apply(ConnectionEvent::Connect);
+        info!("#{} connecting to {}...", index, server);
+        let outcome = self.session(index, &server, &queue).await;
+        // Every way out of a session ends here, so the state can never stay `Ready` for a
+        // connection that is gone.
+        machine.apply(ConnectionEvent::Closed);
+        if let Outcome::ConnectFailed | Outcome::HandshakeFailed(_) = outcome {
+            self.peers.record(&server, false);
+        }
+        self.client.health().record(&server, outcome);
+        self.sessions.lock().unwrap()[index] = Session::default();
+        info!("#{} connection to {} closed", index, server);
+    }
+
+    /// Runs a session until it ends, returning how it ended.
+    async fn session(&self, index: usize, server: &str, queue: &OutboundQueue<N>) -> Outcome {
+        let socket =
+            match resolve::connect(self.client.proxy(), server, Duration::from_secs(5)).await {
+                Ok(socket) => socket,
+                Err(e) => {
+                    error!("#{} failed to connect to beacon: {}", index, e);
+                    return Outcome::ConnectFailed;
+                }
+            };
+        let machine = &self.machines[index];
+        machine.apply(ConnectionEvent::Connected);
+        info!("#{} connected to {}", index, server);
+        let mut framed = Framed::new(socket, MessageCodec::default());
+        // Keep our nonce, the beacon has to sign it with the address it advertises.
+        let our_nonce: u64 = OsRng.gen();
+        let mut peer_address = None;
+        // Set once the peer signed our nonce, nothing but the handshake is taken before that.
+        let mut verified = false;
+        // A response to our challenge that came before the peer's own challenge, it can only be
+        // checked once we know the address the peer claims.
+        let mut held_response: Option<ChallengeResponse<N>> = None;
+        let challenge_request = self.challenge_request(our_nonce);
+        if let Err(e) = self.send(index, &mut framed, challenge_request).await {
+            error!("#{} error sending challenge request: {}", index, e);
+        } else {
+            debug!("#{} sent challenge request", index);
+        }
+        let handshake_deadline = Instant::now() + self.config.handshake_timeout;
+        let mut last_inbound = Instant::now();
+        let mut ping_sent: Option<Instant> = None;
+        let mut resubmitted = false;
+        let mut ping_timer = interval_at(
+            Instant::now() + self.config.ping_interval,
+            self.config.ping_interval,
+        );
         loop {
-            info!("Connecting to server...");
-            let server = client.servers.choose(rng).unwrap();
-            match timeout(Duration::from_secs(5), TcpStream::connect(server)).await {
-                Ok(socket) => match socket {
-                    Ok(socket) => {
-                        info!("Connected to {}", server);
-                        let mut framed = Framed::new(socket, MessageCodec::default());
-                        let challenge_request = Message::ChallengeRequest(ChallengeRequest {
-                            version: Message::VERSION,
-                            listener_port: 4140,
-                            node_type: NodeType::Prover,
-                            address: random_account.address(),
-                            nonce: rng.gen(),
-                        });
-                        if let Err(e) = framed.send(challenge_request).await {
-                            error!("Error sending challenge request: {}", e);
-                        } else {
-                            debug!("Sent challenge request");
+            tokio::select! {
+                _ = sleep_until(handshake_deadline), if machine.state() == ConnectionState::Handshaking => {
+                    error!("#{} handshake timed out", index);
+                    return Outcome::HandshakeFailed("handshake timed out".to_string());
+                }
+                _ = sleep_until(last_inbound + self.config.idle_timeout) => {
+                    error!("#{} no message from beacon for {}s", index, self.config.idle_timeout.as_secs());
+                    return Outcome::Disconnected("idle timeout".to_string());
+                }
+                _ = ping_timer.tick(), if machine.state() == ConnectionState::Ready => {
+                    if ping_sent.is_some() {
+                        // Still waiting for the last `Pong`, the idle timeout takes care of it.
+                        continue;
+                    }
+                    let message = Message::Ping(Ping {
+                        version: Message::<N>::VERSION,
+                        node_type: NodeType::Prover,
+                        block_locators: None,
+                    });
+                    if let Err(e) = self.send(index, &mut framed, message).await {
+                        error!("#{} error sending ping: {:?}", index, e);
+                    } else {
+                        debug!("#{} sent ping", index);
+                        ping_sent = Some(Instant::now());
+                    }
+                }
+                Outbound { message, .. } = queue.pop() => {
+                    let name = message.name().to_string();
+                    info!("#{} sending {} to beacon", index, name);
+                    if let Err(e) = self.send(index, &mut framed, message).await {
+                        error!("#{} error sending {}: {:?}", index, name, e);
+                    }
+                }
+                result = framed.next() => match result {
+                    Some(Ok(message)) => {
+                        last_inbound = Instant::now();
+                        if let Some(recorder) = &self.config.recorder {
+                            recorder.record(index, Direction::Inbound, &message);
                         }
-                        let receiver = &mut *receiver.lock().await;
-                        loop {
-                            tokio::select! {
-                                Some(message) = receiver.recv() => {
-                                    let m = message.clone();
-                                    let name = m.name();
-                                    info!("Sending {} to beacon", name);
-                                    if let Err(e) = framed.send(message).await {
-                                        error!("Error sending {}: {:?}", name, e);
+                        debug!("#{} received {} from beacon", index, message.name());
+                        if machine.state() != ConnectionState::Ready
+                            && matches!(message, Message::PuzzleResponse(_) | Message::UnconfirmedSolution(_) | Message::PeerResponse(_))
+                        {
+                            warn!("#{} ignoring {} before the handshake completed", index, message.name());
+                            continue;
+                        }
+                        // A challenge response with the address to check it against.
+                        let mut to_verify = None;
+                        match message {
+                            Message::ChallengeRequest(ChallengeRequest {
+                                version,
+                                listener_port: _,
+                                node_type,
+                                address,
+                                nonce,
+                            }) => {
+                                if peer_address.is_some() {
+                                    error!("#{} peer sent a second challenge request", index);
+                                    return Outcome::HandshakeFailed("duplicate challenge request".to_string());
+                                }
+                                if let Err(e) = self.check_peer(version, node_type, &address) {
+                                    error!("#{} {}", index, e);
+                                    return Outcome::HandshakeFailed(e);
+                                }
+                                peer_address = Some(address);
+                                let response = Message::ChallengeResponse(ChallengeResponse {
+                                    genesis_header: self.genesis_header,
+                                    signature: Data::Object(self.account.sign_bytes(&nonce.to_le_bytes(), &mut OsRng).unwrap()),
+                                });
+                                if let Err(e) = self.send(index, &mut framed, response).await {
+                                    error!("#{} error sending challenge response: {:?}", index, e);
+                                } else {
+                                    debug!("#{} sent challenge response", index);
+                                }
+                                to_verify = held_response.take().map(|response| (response, address));
+                            }
+                            Message::ChallengeResponse(message) => {
+                                if verified || held_response.is_some() {
+                                    error!("#{} peer sent a second challenge response", index);
+                                    return Outcome::HandshakeFailed("duplicate challenge response".to_string());
+                                }
+                                if message.genesis_header != self.genesis_header {
+                                    error!("#{} peer has a different genesis block", index);
+                                    return Outcome::HandshakeFailed("different genesis block".to_string());
+                                }
+                                match peer_address {
+                                    Some(peer_address) => to_verify = Some((message, peer_address)),
+                                    None => {
+                                        debug!("#{} holding challenge response until the peer's challenge", index);
+                                        held_response = Some(message);
                                     }
                                 }
-                                result = framed.next() => match result {
-                                    Some(Ok(message)) => {
-                                        debug!("Received {} from beacon", message.name());
-                                        match message {
-                                            Message::ChallengeRequest(ChallengeRequest {
-                                                version,
-                                                listener_port: _,
-                                                node_type,
-                                                address: _,
-                                                nonce,
-                                            }) => {
-                                                if version < Message::VERSION {
-                                                    error!("Peer is running an older version of the protocol");
-                                                    sleep(Duration::from_secs(5)).await;
-                                                    break;
-                                                }
-                                                if node_type != NodeType::Beacon && node_type != NodeType::Validator {
-                                                    error!("Peer is not a beacon or validator");
-                                                    sleep(Duration::from_secs(5)).await;
-                                                    break;
-                                                }
-                                                let response = Message::ChallengeResponse(ChallengeResponse {
-                                                    genesis_header,
-                                                    signature: Data::Object(random_account.sign_bytes(&nonce.to_le_bytes(), rng).unwrap()),
-                                                });
-                                                if let Err(e) = framed.send(response).await {
-                                                    error!("Error sending challenge response: {:?}", e);
-                                                } else {
-                                                    debug!("Sent challenge response");
-                                                }
-                                            }
-                                            Message::ChallengeResponse(message) => {
-                                                match message.genesis_header == genesis_header {
-                                                    true => {
-                                                        // Send the first `Ping` message to the peer.
-                                                        let message = Message::Ping(Ping {
-                                                            version: Message::VERSION,
-                                                            node_type: NodeType::Prover,
-                                                            block_locators: None,
-                                                        });
-                                                        if let Err(e) = framed.send(message).await {
-                                                            error!("Error sending ping: {:?}", e);
-                                                        } else {
-                                                            debug!("Sent ping");
-                                                        }
-                                                    }
-                                                    false => {
-                                                        error!("Peer has a different genesis block");
-                                                        sleep(Duration::from_secs(5)).await;
-                                                        break;
-                                                    }
-                                                }
-                                            }
-                                            Message::Ping(_) => {
-                                                let pong = Message::Pong(Pong { is_fork: None });
-                                                if let Err(e) = framed.send(pong).await {
-                                                    error!("Error sending pong: {:?}", e);
-                                                } else {
-                                                    debug!("Sent pong");
-                                                }
-                                                let message = Message::Ping(Ping {
-                                                    version: Message::VERSION,
-                                                    node_type: NodeType::Prover,
-                                                    block_locators: None,
-                                                });
-                                                if let Err(e) = framed.send(message).await {
-                                                    error!("Error sending ping: {:?}", e);
-                                                } else {
-                                                    debug!("Sent ping");
-                                                }
-                                            }
-                                            Message::Pong(_) => {
-                                                let was_connected = connected.load(Ordering::SeqCst);
-                                                connected.store(true, Ordering::SeqCst);
-                                                if !was_connected {
-                                                    if let Err(e) = framed.send(Message::PuzzleRequest(PuzzleRequest {})).await {
-                                                        error!("Failed to send puzzle request: {}", e);
-                                                    }
-                                                }
-                                            }
-                                            Message::PuzzleResponse(PuzzleResponse {
-                                                epoch_challenge, block_header
-                                            }) => {
-                                                let block_header = match block_header.deserialize().await {
-                                                    Ok(block_header) => block_header,
-                                                    Err(error) => {
-                                                        error!("Error deserializing block header: {:?}", error);
-                                                        sleep(Duration::from_secs(5)).await;
-                                                        break;
-                                                    }
-                                                };
-                                                if let Err(e) = prover.sender().send(ProverEvent::NewTarget(block_header.proof_target())).await {
-                                                    error!("Error sending new target to prover: {}", e);
-                                                } else {
-                                                    debug!("Sent new target to prover");
-                                                }
-                                                if let Err(e) = prover.sender().send(ProverEvent::NewWork(epoch_challenge.epoch_number(), epoch_challenge, client.address())).await {
-                                                    error!("Error sending new work to prover: {}", e);
-                                                } else {
-                                                    debug!("Sent new work to prover");
-                                                }
-                                            }
-                                            Message::Disconnect(message) => {
-                                                error!("Peer disconnected: {:?}", message.reason);
-                                                sleep(Duration::from_secs(5)).await;
-                                                break;
-                                            }
-                                            _ => {
-                                                debug!("Unhandled message: {}", message.name());
-                                            }
+                            }
+                            Message::Ping(_) => {
+                                let pong = Message::Pong(Pong { is_fork: None });
+                                if let Err(e) = self.send(index, &mut framed, pong).await {
+                                    error!("#{} error sending pong: {:?}", index, e);
+                                } else {
+                                    debug!("#{} sent pong", index);
+                                }
+                            }
+                            Message::Pong(_) => {
+                                if let Some(sent) = ping_sent.take() {
+                                    let rtt = sent.elapsed();
+                                    debug!("#{} ping RTT {}ms", index, rtt.as_millis());
+                                    self.client.health().record(server, Outcome::Rtt(rtt));
+                                }
+                                if machine.state() == ConnectionState::Handshaking {
+                                    if !verified {
+                                        error!("#{} peer answered a ping it was never sent", index);
+                                        return Outcome::HandshakeFailed("pong before challenge response".to_string());
+                                    }
+                                    machine.apply(ConnectionEvent::HandshakeComplete);
+                                    self.client.health().record(server, Outcome::Ready);
+                                    self.peers.record(server, true);
+                                    info!("#{} ready on {}", index, server);
+                                    if let Err(e) = self.send(index, &mut framed, Message::PuzzleRequest(PuzzleRequest {})).await {
+                                        error!("#{} failed to send puzzle request: {}", index, e);
+                                    }
+                                    if let Err(e) = self.send(index, &mut framed, Message::PeerRequest(PeerRequest)).await {
+                                        error!("#{} failed to send peer request: {}", index, e);
+                                    }
+                                }
+                            }
+                            Message::PuzzleResponse(PuzzleResponse {
+                                epoch_challenge, block_header
+                            }) => {
+                                let block_header = match block_header.deserialize().await {
+                                    Ok(block_header) => block_header,
+                                    Err(error) => {
+                                        error!("#{} error deserializing block header: {:?}", index, error);
+                                        return Outcome::Disconnected("invalid block header".to_string());
+                                    }
+                                };
+                                if let Err(e) = self.new_work(index, epoch_challenge, block_header).await {
+                                    error!("#{} sent bogus work: {}", index, e);
+                                    return Outcome::Disconnected(e);
+                                }
+                                // Only now the epoch is known to be current, so solutions held
+                                // during the outage can't go out stale.
+                                if !resubmitted {
+                                    resubmitted = true;
+                                    for message in self.take_held() {
+                                        info!("#{} resubmitting held solution", index);
+                                        if let Err(e) = self.send(index, &mut framed, message).await {
+                                            error!("#{} error resubmitting solution: {:?}", index, e);
                                         }
                                     }
-                                    Some(Err(e)) => {
-                                        warn!("Failed to read the message: {:?}", e);
+                                }
+                            }
+                            Message::UnconfirmedSolution(message) => {
+                                if self.tracker.confirm(&message.puzzle_commitment) {
+                                    let result = ProverEvent::Result(true, Some(format!("echoed by {}", server)));
+                                    if let Err(e) = self.prover.sender().send(result).await {
+                                        error!("#{} error sending share result to prover: {}", index, e);
                                     }
-                                    None => {
-                                        error!("Disconnected from beacon");
-                                        connected.store(false, Ordering::SeqCst);
-                                        sleep(Duration::from_secs(5)).await;
-                                        break;
+                                }
+                            }
+                            Message::PeerRequest(_) => {
+                                // We have no peers worth sharing.
+                                let response = Message::PeerResponse(PeerResponse { peers: Vec::new() });
+                                if let Err(e) = self.send(index, &mut framed, response).await {
+                                    error!("#{} error sending peer response: {:?}", index, e);
+                                }
+                            }
+                            Message::PeerResponse(PeerResponse { peers }) => {
+                                debug!("#{} learned {} peers", index, peers.len());
+                                let mut discovered = self.discovered.lock().unwrap();
+                                for peer in peers {
+                                    if discovered.len() < MAX_DISCOVERED && !discovered.contains(&peer) {
+                                        discovered.push(peer);
                                     }
                                 }
                             }
+                            Message::Disconnect(message) => {
+                                error!("#{} peer disconnected: {:?}", index, message.reason);
+                                let reason = format!("{:?}", message.reason);
+                                return match machine.state() {
+                                    ConnectionState::Ready => Outcome::Disconnected(reason),
+                                    _ => Outcome::HandshakeFailed(reason),
+                                };
+                            }
+                            _ => {
+                                debug!("#{} unhandled message: {}", index, message.name());
+                            }
+                        }
+                        if let Some((response, peer_address)) = to_verify {
+                            let signed = match response.signature.deserialize().await {
+                                Ok(signature) => signature.verify_bytes(&peer_address, &our_nonce.to_le_bytes()),
+                                Err(e) => {
+                                    error!("#{} error deserializing challenge signature: {:?}", index, e);
+                                    false
+                                }
+                            };
+                            if !signed {
+                                error!("#{} peer {} failed to sign our challenge", index, peer_address);
+                                return Outcome::HandshakeFailed("invalid challenge signature".to_string());
+                            }
+                            debug!("#{} verified challenge signature of {}", index, peer_address);
+                            verified = true;
+                            // Send the first `Ping` message to the peer.
+                            let message = Message::Ping(Ping {
+                                version: Message::<N>::VERSION,
+                                node_type: NodeType::Prover,
+                                block_locators: None,
+                            });
+                            if let Err(e) = self.send(index, &mut framed, message).await {
+                                error!("#{} error sending ping: {:?}", index, e);
+                            } else {
+                                debug!("#{} sent ping", index);
+                                ping_sent = Some(Instant::now());
+                            }
                         }
                     }
-                    Err(e) => {
-                        error!("Failed to connect to beacon: {}", e);
-                        sleep(Duration::from_secs(5)).await;
+                    Some(Err(e)) => {
+                        warn!("#{} failed to read the message: {:?}", index, e);
+                    }
+                    None => {
+                        error!("#{} disconnected from beacon", index);
+                        return Outcome::Disconnected("connection closed".to_string());
                     }
-                },
-                Err(_) => {
-                    error!("Failed to connect to beacon: Timed out");
-                    sleep(Duration::from_secs(5)).await;
                 }
             }
         }
-    });
+    }
 }
@@ -0,0 +1,290 @@
+use std::{
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use rayon::ThreadPool;
+use snarkvm::{
+    console::account::address::Address,
+    prelude::{CoinbasePuzzle, Network},
+    synthesizer::{EpochChallenge, ProverSolution},
+};
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+
+use crate::nonce::NonceRange;
+
+/// Work for every pool: the challenge of one epoch and each pool's nonce range in it.
+pub struct Job<N: Network> {
+    pub epoch_number: u32,
+    pub epoch_challenge: EpochChallenge<N>,
+    pub address: Address<N>,
+    pub nonces: Vec<Arc<NonceRange>>,
+    /// When the work arrived, to measure how long pools take to switch to it.
+    pub received: Instant,
+}
+
+#[allow(clippy::large_enum_variant)]
+pub enum EngineEvent<N: Network> {
+    /// A pool started its first proof for a new job.
+    Switched {
+        pool: usize,
+        epoch_number: u32,
+        latency: Duration,
+        /// The job arrived while the pool was proving for the previous one, that proof was
+        /// abandoned instead of waited for.
+        abandoned: bool,
+    },
+    /// A proof attempt finished, with a solution if it met the proof target.
+    Proved {
+        pool: usize,
+        epoch_number: u32,
+        nonce: u64,
+        solution: Option<ProverSolution<N>>,
+        elapsed: Duration,
+    },
+    /// A pool used up its nonce range and waits for the next job.
+    Exhausted { pool: usize, epoch_number: u32 },
+}
+
+/// The job every pool works on, and the attempts that finished since each pool's loop last
+/// looked. Loops and attempts wait on `changed` for either.
+struct Slot<N: Network> {
+    state: Mutex<State<N>>,
+    changed: Condvar,
+}
+
+struct State<N: Network> {
+    generation: u64,
+    job: Option<Arc<Job<N>>>,
+    /// Finished attempts by pool, abandoned ones included until their loop drops them.
+    finished: Vec<Vec<Attempt<N>>>,
+}
+
+/// The outcome of one `prove` call, tagged with the generation of the job it was for.
+struct Attempt<N: Network> {
+    generation: u64,
+    epoch_number: u32,
+    nonce: u64,
+    solution: Option<ProverSolution<N>>,
+    elapsed: Duration,
+}
+
+/// One long-lived proving loop per thread pool.
+///
+/// Each loop runs on its own thread for the life of the process, takes the current [`Job`]
+/// from a shared slot, hands one attempt at a time to its pool and reports every attempt as an
+/// [`EngineEvent`].
+///
+/// snarkVM offers no way to stop a proof, so a new job abandons the attempt in progress
+/// instead: the loop wakes up as soon as the job changes, stops waiting for the attempt and
+/// hands the new job's first proof to the pool right away. The pool's threads pick it up
+/// between two pieces of the old proof's parallel work, within milliseconds, and share the
+/// pool with the old proof until it returns and its result is dropped. The new proof runs
+/// slower meanwhile but never waits for the old one. [`EngineEvent::Switched`] reports such
+/// switches as `abandoned`, once the new proof has started.
+pub struct ProvingEngine<N: Network> {
+    slot: Arc<Slot<N>>,
+    pools: usize,
+}
+
+impl<N: Network> ProvingEngine<N> {
+    /// Starts the loops, they idle until the first job.
+    pub fn start(
+        thread_pools: &[Arc<ThreadPool>],
+        coinbase_puzzle: Arc<CoinbasePuzzle<N>>,
+        proof_target: Arc<AtomicU64>,
+        events: mpsc::UnboundedSender<EngineEvent<N>>,
+    ) -> Self {
+        let slot = Arc::new(Slot {
+            state: Mutex::new(State {
+                generation: 0,
+                job: None,
+                finished: thread_pools.iter().map(|_| Vec::new()).collect(),
+            }),
+            changed: Condvar::new(),
+        });
+        for (pool, tp) in thread_pools.iter().enumerate() {
+            let slot = slot.clone();
+            let tp = tp.clone();
+            // Every loop proves with the same key, only the `Arc` is cloned.
+            let coinbase_puzzle = coinbase_puzzle.clone();
+            let proof_target = proof_target.clone();
+            let events = events.clone();
+            // The loop mostly sleeps, it runs beside the pool so every thread of the pool is
+            // left to the proofs.
+            thread::spawn(move || {
+                prove_loop(pool, &slot, &tp, &coinbase_puzzle, &proof_target, &events)
+            });
+        }
+        debug!("Started {} proving loops", thread_pools.len());
+        Self {
+            slot,
+            pools: thread_pools.len(),
+        }
+    }
+
+    pub fn pools(&self) -> usize {
+        self.pools
+    }
+
+    /// Replaces the job of every pool.
+    pub fn set_job(&self, job: Job<N>) {
+        let mut state = self.slot.state.lock().unwrap();
+        state.generation += 1;
+        state.job = Some(Arc::new(job));
+        self.slot.changed.notify_all();
+    }
+}
+
+fn prove_loop<N: Network>(
+    pool: usize,
+    slot: &Arc<Slot<N>>,
+    tp: &ThreadPool,
+    coinbase_puzzle: &Arc<CoinbasePuzzle<N>>,
+    proof_target: &AtomicU64,
+    events: &mpsc::UnboundedSender<EngineEvent<N>>,
+) {
+    let mut generation = 0;
+    // Whether the last job changed while a proof for it was running.
+    let mut abandoned = false;
+    loop {
+        let job = {
+            let mut state = slot.state.lock().unwrap();
+            while state.generation == generation {
+                state = slot.changed.wait(state).unwrap();
+            }
+            generation = state.generation;
+            match &state.job {
+                Some(job) => job.clone(),
+                None => continue,
+            }
+        };
+        let nonces = match job.nonces.get(pool) {
+            Some(nonces) => nonces,
+            None => {
+                warn!(
+                    "Pool {} has no nonce range in epoch {}",
+                    pool, job.epoch_number
+                );
+                continue;
+            }
+        };
+        let mut first = true;
+        loop {
+            let nonce = match nonces.next_nonce() {
+                Some(nonce) => nonce,
+                None => {
+                    let _ = events.send(EngineEvent::Exhausted {
+                        pool,
+                        epoch_number: job.epoch_number,
+                    });
+                    break;
+                }
+            };
+            // The switch is reported once the first proof actually starts on the pool.
+            let switched = if std::mem::take(&mut first) {
+                Some((events.clone(), std::mem::take(&mut abandoned)))
+            } else {
+                None
+            };
+            let attempt_slot = slot.clone();
+            let attempt_job = job.clone();
+            let attempt_puzzle = coinbase_puzzle.clone();
+            let target = proof_target.load(Ordering::SeqCst);
+            tp.spawn(move || {
+                if let Some((events, abandoned)) = switched {
+                    let _ = events.send(EngineEvent::Switched {
+                        pool,
+                        epoch_number: attempt_job.epoch_number,
+                        latency: attempt_job.received.elapsed(),
+                        abandoned,
+                    });
+                }
+                let started = Instant::now();
+                let result = catch_unwind(AssertUnwindSafe(|| {
+                    attempt_puzzle.prove(
+                        &attempt_job.epoch_challenge,
+                        attempt_job.address,
+                        nonce,
+                        Some(target),
+                    )
+                }));
+                let attempt = Attempt {
+                    generation,
+                    epoch_number: attempt_job.epoch_number,
+                    nonce,
+                    solution: match result {
+                        Ok(Ok(solution)) => Some(solution),
+                        Ok(Err(_)) => None,
+                        Err(_) => {
+                            warn!("Pool {} panicked proving nonce {}", pool, nonce);
+                            None
+                        }
+                    },
+                    elapsed: started.elapsed(),
+                };
+                let mut state = attempt_slot.state.lock().unwrap();
+                state.finished[pool].push(attempt);
+                attempt_slot.changed.notify_all();
+            });
+
+            let attempt = match wait_for_attempt(pool, slot, generation) {
+                Some(attempt) => attempt,
+                None => {
+                    debug!(
+                        "Abandoning proof for epoch {} with nonce {}, the job changed",
+                        job.epoch_number, nonce
+                    );
+                    abandoned = true;
+                    break;
+                }
+            };
+            let event = EngineEvent::Proved {
+                pool,
+                epoch_number: attempt.epoch_number,
+                nonce: attempt.nonce,
+                solution: attempt.solution,
+                elapsed: attempt.elapsed,
+            };
+            if events.send(event).is_err() {
+                // Nobody is listening anymore, the prover is gone.
+                return;
+            }
+        }
+    }
+}
+
+/// Waits for the attempt `pool` is running for `generation`, or for the job to change first.
+/// Attempts of earlier jobs that finish meanwhile are dropped.
+fn wait_for_attempt<N: Network>(
+    pool: usize,
+    slot: &Slot<N>,
+    generation: u64,
+) -> Option<Attempt<N>> {
+    let mut state = slot.state.lock().unwrap();
+    loop {
+        let (current, stale): (Vec<_>, Vec<_>) = std::mem::take(&mut state.finished[pool])
+            .into_iter()
+            .partition(|attempt| attempt.generation == generation);
+        state.finished[pool] = current;
+        for stale in stale {
+            debug!(
+                "Dropping abandoned proof for epoch {} with nonce {}",
+                stale.epoch_number, stale.nonce
+            );
+        }
+        if let Some(attempt) = state.finished[pool].pop() {
+            return Some(attempt);
+        }
+        if state.generation != generation {
+            return None;
+        }
+        state = slot.changed.wait(state).unwrap();
+    }
+}
@@ -0,0 +1,127 @@
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Tells apart the temporary files of concurrent writes within this process.
+static NEXT_TEMP: AtomicU64 = AtomicU64::new(0);
+
+/// Writes `bytes` to `path` through a temporary file next to it and a rename, so a crash never
+/// leaves a truncated file behind. `mode` sets the permissions of the file on Unix.
+///
+/// The temporary file is named after the full file name, the process and the write, and is
+/// always created fresh, so it never shares a name or leftover permissions with another file.
+pub fn write_atomic(path: &Path, bytes: &[u8], mode: Option<u32>) -> io::Result<()> {
+    let temp = temp_path(path)?;
+    // Only a crashed write of ours can have left it behind.
+    let _ = fs::remove_file(&temp);
+    let result = write_new(&temp, bytes, mode).and_then(|()| fs::rename(&temp, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+fn temp_path(path: &Path) -> io::Result<PathBuf> {
+    let name = path.file_name().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not a file path", path.display()),
+        )
+    })?;
+    let mut temp = name.to_os_string();
+    temp.push(format!(
+        ".{}.{}.tmp",
+        std::process::id(),
+        NEXT_TEMP.fetch_add(1, Ordering::SeqCst)
+    ));
+    Ok(path.with_file_name(temp))
+}
+
+fn write_new(temp: &Path, bytes: &[u8], mode: Option<u32>) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        if let Some(mode) = mode {
+            options.mode(mode);
+        }
+    }
+    let mut file = options.open(temp)?;
+    // The mode given at creation is narrowed by the umask, set it exactly.
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if let Some(mode) = mode {
+            file.set_permissions(fs::Permissions::from_mode(mode))?;
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+    file.write_all(bytes)?;
+    file.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("aleo-prover-files-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn entries(dir: &Path) -> Vec<String> {
+        let mut entries = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        entries.sort();
+        entries
+    }
+
+    #[test]
+    fn replaces_the_file_without_leaving_the_temporary_one() {
+        let dir = test_dir("replace");
+        let path = dir.join("key.txt");
+
+        write_atomic(&path, b"first", None).unwrap();
+        write_atomic(&path, b"second", None).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"second");
+        assert_eq!(entries(&dir), ["key.txt"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn files_sharing_a_stem_keep_their_own_content() {
+        let dir = test_dir("stem");
+        write_atomic(&dir.join("peer.key"), b"key", None).unwrap();
+        write_atomic(&dir.join("peer.json"), b"[]", None).unwrap();
+        assert_eq!(fs::read(dir.join("peer.key")).unwrap(), b"key");
+        assert_eq!(fs::read(dir.join("peer.json")).unwrap(), b"[]");
+        assert_eq!(entries(&dir), ["peer.json", "peer.key"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn mode_is_exact_even_over_a_readable_file() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = test_dir("mode");
+        let path = dir.join("id.key");
+        fs::write(&path, b"old").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        write_atomic(&path, b"secret", Some(0o600)).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,217 @@
+use std::{
+    cmp::Ordering,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use rand::{prelude::SliceRandom, thread_rng};
+use tracing::{debug, info, warn};
+
+use crate::resolve::BeaconEntry;
+
+/// Number of consecutive failures after which a beacon gets banned.
+const BAN_THRESHOLD: u32 = 3;
+const BAN_BASE: Duration = Duration::from_secs(30);
+const BAN_MAX: Duration = Duration::from_secs(30 * 60);
+
+/// What happened the last time we talked to a beacon.
+pub enum Outcome {
+    /// TCP connect failed or timed out.
+    ConnectFailed,
+    /// The beacon failed the handshake (genesis mismatch, old version, wrong node type, ...).
+    HandshakeFailed(String),
+    /// The session was closed, by the beacon or by us.
+    Disconnected(String),
+    /// The handshake completed.
+    Ready,
+    /// A `Ping`/`Pong` round trip completed.
+    Rtt(Duration),
+}
+
+#[derive(Debug)]
+struct Stats {
+    server: String,
+    weight: u32,
+    connect_failures: u32,
+    handshake_failures: u32,
+    disconnects: u32,
+    sessions: u32,
+    consecutive_failures: u32,
+    rtt: Option<Duration>,
+    banned_until: Option<Instant>,
+}
+
+impl Stats {
+    fn new(entry: &BeaconEntry) -> Self {
+        Self {
+            server: entry.server.clone(),
+            weight: entry.weight,
+            connect_failures: 0,
+            handshake_failures: 0,
+            disconnects: 0,
+            sessions: 0,
+            consecutive_failures: 0,
+            rtt: None,
+            banned_until: None,
+        }
+    }
+
+    /// Higher is healthier. Failures are weighted against completed sessions so that a beacon
+    /// which had a bad minute a long time ago can recover. The configured weight scales the
+    /// result, so it only matters among beacons that are doing fine.
+    fn score(&self) -> f64 {
+        let failures = self.connect_failures as f64 * 10.0
+            + self.handshake_failures as f64 * 20.0
+            + self.disconnects as f64 * 5.0;
+        let rtt = self
+            .rtt
+            .map(|rtt| rtt.as_millis() as f64 / 10.0)
+            .unwrap_or(0.0);
+        (100.0 - failures / (self.sessions as f64 + 1.0) - rtt).max(0.0) * self.weight as f64
+    }
+
+    fn is_banned(&self, now: Instant) -> bool {
+        self.banned_until.map(|until| until > now).unwrap_or(false)
+    }
+
+    fn fail(&mut self) {
+        self.consecutive_failures += 1;
+        if self.consecutive_failures >= BAN_THRESHOLD {
+            let exponent = (self.consecutive_failures - BAN_THRESHOLD).min(16);
+            let ban = BAN_BASE.saturating_mul(1 << exponent).min(BAN_MAX);
+            warn!(
+                "Banning beacon {} for {}s after {} consecutive failures",
+                self.server,
+                ban.as_secs(),
+                self.consecutive_failures
+            );
+            self.banned_until = Some(Instant::now() + ban);
+        }
+    }
+}
+
+/// Health bookkeeping for the configured beacons.
+pub struct BeaconHealth {
+    stats: Mutex<Vec<Stats>>,
+}
+
+impl BeaconHealth {
+    pub fn new(beacons: &[BeaconEntry]) -> Self {
+        Self {
+            stats: Mutex::new(beacons.iter().map(Stats::new).collect()),
+        }
+    }
+
+    /// Adds beacons that are not tracked yet and updates the weight of known ones.
+    pub fn add(&self, beacons: &[BeaconEntry]) {
+        let mut stats = self.stats.lock().unwrap();
+        for beacon in beacons {
+            match stats.iter_mut().find(|s| s.server == beacon.server) {
+                Some(known) => known.weight = beacon.weight,
+                None => {
+                    info!("Added beacon {}", beacon.server);
+                    stats.push(Stats::new(beacon));
+                }
+            }
+        }
+    }
+
+    pub fn contains(&self, server: &str) -> bool {
+        self.stats
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|s| s.server == server)
+    }
+
+    pub fn record(&self, server: &str, outcome: Outcome) {
+        let mut stats = self.stats.lock().unwrap();
+        let stats = match stats.iter_mut().find(|s| s.server == server) {
+            Some(stats) => stats,
+            None => return,
+        };
+        match outcome {
+            Outcome::ConnectFailed => {
+                stats.connect_failures += 1;
+                stats.fail();
+            }
+            Outcome::HandshakeFailed(reason) => {
+                warn!("Beacon {} failed the handshake: {}", server, reason);
+                stats.handshake_failures += 1;
+                stats.fail();
+            }
+            Outcome::Disconnected(reason) => {
+                debug!("Beacon {} disconnected: {}", server, reason);
+                stats.disconnects += 1;
+            }
+            Outcome::Ready => {
+                stats.sessions += 1;
+                stats.consecutive_failures = 0;
+                stats.banned_until = None;
+            }
+            Outcome::Rtt(rtt) => {
+                // Smooth the RTT so a single slow round trip does not reshuffle the ranking.
+                stats.rtt = Some(match stats.rtt {
+                    Some(previous) => (previous * 7 + rtt) / 8,
+                    None => rtt,
+                });
+            }
+        }
+    }
+
+    /// Picks the healthiest beacon that is not banned. If every beacon is banned, the one whose
+    /// ban expires first is returned.
+    ///
+    /// Beacons in `exclude` are only considered when nothing else is left.
+    pub fn pick(&self, exclude: &[String]) -> String {
+        let now = Instant::now();
+        let mut stats = self.stats.lock().unwrap();
+        // Shuffle first so beacons with equal scores share the load.
+        stats.shuffle(&mut thread_rng());
+        stats.sort_by(|a, b| {
+            a.is_banned(now)
+                .cmp(&b.is_banned(now))
+                .then_with(|| b.score().partial_cmp(&a.score()).unwrap_or(Ordering::Equal))
+        });
+        let ranking = stats
+            .iter()
+            .map(|s| {
+                let rtt = s
+                    .rtt
+                    .map(|rtt| format!("{}ms", rtt.as_millis()))
+                    .unwrap_or_else(|| "---".to_string());
+                let banned = match s.banned_until {
+                    Some(until) if until > now => format!(" banned {}s", (until - now).as_secs()),
+                    _ => String::new(),
+                };
+                format!("{} ({:.1}, rtt {}{})", s.server, s.score(), rtt, banned)
+            })
+            .collect::<Vec<_>>();
+        info!("Beacon ranking: {}", ranking.join(", "));
+        let mut candidates = stats
+            .iter()
+            .filter(|s| !exclude.contains(&s.server))
+            .peekable();
+        let candidates: Vec<_> = match candidates.peek() {
+            Some(_) => candidates.collect(),
+            None => stats.iter().collect(),
+        };
+        let best = match candidates.iter().find(|s| !s.is_banned(now)) {
+            Some(best) => best,
+            None => candidates.iter().min_by_key(|s| s.banned_until).unwrap(),
+        };
+        best.server.clone()
+    }
+
+    /// How long `server` stays banned, if it is banned at all.
+    pub fn banned_for(&self, server: &str) -> Option<Duration> {
+        let now = Instant::now();
+        let stats = self.stats.lock().unwrap();
+        stats
+            .iter()
+            .find(|s| s.server == server)
+            .and_then(|s| s.banned_until)
+            .filter(|until| *until > now)
+            .map(|until| until - now)
+    }
+}
@@ -0,0 +1,52 @@
+use std::{fs, path::Path, str::FromStr};
+
+use anyhow::{anyhow, Result};
+use rand::rngs::OsRng;
+use snarkos_account::Account;
+use snarkvm::prelude::{Network, PrivateKey};
+use tracing::info;
+
+use crate::files;
+
+/// Loads the peer identity stored at `path`, creating it first if the file does not exist.
+///
+/// The peer identity is the key the prover signs handshakes with. It is unrelated to the
+/// payout address and never holds funds.
+pub fn load_or_create<N: Network>(path: &Path) -> Result<Account<N>> {
+    if !path.exists() {
+        return create(path, false);
+    }
+    let private_key = fs::read_to_string(path)
+        .map_err(|e| anyhow!("Unable to read peer identity {}: {}", path.display(), e))?;
+    let private_key = PrivateKey::<N>::from_str(private_key.trim())
+        .map_err(|e| anyhow!("Invalid peer identity {}: {}", path.display(), e))?;
+    let account = Account::try_from(private_key)?;
+    info!("Loaded peer identity {}", account.address());
+    Ok(account)
+}
+
+/// Generates a new peer identity and writes it to `path`. An existing file is only replaced
+/// when `rotate` is set.
+pub fn create<N: Network>(path: &Path, rotate: bool) -> Result<Account<N>> {
+    if path.exists() && !rotate {
+        return Err(anyhow!(
+            "Peer identity {} already exists, rotate it instead",
+            path.display()
+        ));
+    }
+    let private_key = PrivateKey::<N>::new(&mut OsRng)?;
+    let account = Account::try_from(private_key)?;
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // A rotation never leaves a half-written key behind, and only we can read it.
+    files::write_atomic(path, format!("{}\n", private_key).as_bytes(), Some(0o600))?;
+
+    info!(
+        "Wrote peer identity {} to {}",
+        account.address(),
+        path.display()
+    );
+    Ok(account)
+}
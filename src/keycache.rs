@@ -0,0 +1,144 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use bytes::Buf;
+use snarkvm::{
+    prelude::{FromBytes, Network, ToBytes},
+    synthesizer::CoinbaseProvingKey,
+};
+use snarkvm_algorithms::crypto_hash::sha256d_to_u64;
+use tracing::debug;
+
+use crate::files;
+
+/// First bytes of every key cache. Bump the last digit when the file layout changes.
+const MAGIC: &[u8; 8] = b"ALEOPK02";
+
+/// Network ID (u16), degree (u32) and checksum (u64) after [`MAGIC`].
+const HEADER_LEN: usize = MAGIC.len() + 2 + 4 + 8;
+
+/// Where the key is cached unless `--key-cache` says otherwise.
+pub fn default_path<N: Network>() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| {
+        PathBuf::from(home)
+            .join(".aleo-prover")
+            .join(format!("coinbase-{}.key", N::ID))
+    })
+}
+
+/// Reads the key cached at `path`.
+///
+/// The file starts with [`MAGIC`], then the network ID (u16 LE), the puzzle degree (u32 LE),
+/// the checksum of the key (u64 LE, from sha256d) and the key itself. A cache for another
+/// network or degree is refused. Nothing ties the cache to a snarkVM version, a key the build
+/// can't deserialize is rebuilt like any refused cache. After an upgrade that changes how the
+/// key is trimmed from the SRS, delete the cache so it is written again.
+pub fn read<N: Network>(path: &Path, degree: u32) -> Result<CoinbaseProvingKey<N>> {
+    let bytes = fs::read(path)?;
+    let payload = decode(&bytes, N::ID, degree)?;
+    CoinbaseProvingKey::<N>::from_bytes_le(payload)
+}
+
+/// Writes `key` to `path`, see [`read`] for the layout.
+pub fn write<N: Network>(path: &Path, degree: u32, key: &CoinbaseProvingKey<N>) -> Result<()> {
+    let bytes = encode(N::ID, degree, &key.to_bytes_le()?);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    files::write_atomic(path, &bytes, None)?;
+    debug!("Wrote {} bytes to {}", bytes.len(), path.display());
+    Ok(())
+}
+
+/// Puts the header in front of the serialized key.
+fn encode(network: u16, degree: u32, payload: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(HEADER_LEN + payload.len());
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&network.to_le_bytes());
+    bytes.extend_from_slice(&degree.to_le_bytes());
+    bytes.extend_from_slice(&sha256d_to_u64(payload).to_le_bytes());
+    bytes.extend_from_slice(payload);
+    bytes
+}
+
+/// Checks the header and returns the serialized key behind it.
+fn decode(bytes: &[u8], network: u16, degree: u32) -> Result<&[u8]> {
+    if bytes.len() < HEADER_LEN {
+        return Err(anyhow!("truncated header"));
+    }
+    if !bytes.starts_with(MAGIC) {
+        return Err(anyhow!("not a key cache"));
+    }
+    let mut buf = &bytes[MAGIC.len()..];
+    let cached_network = buf.get_u16_le();
+    if cached_network != network {
+        return Err(anyhow!(
+            "made for network {}, this is {}",
+            cached_network,
+            network
+        ));
+    }
+    let cached_degree = buf.get_u32_le();
+    if cached_degree != degree {
+        return Err(anyhow!(
+            "made for degree {}, the puzzle needs {}",
+            cached_degree,
+            degree
+        ));
+    }
+    let checksum = buf.get_u64_le();
+    if sha256d_to_u64(buf) != checksum {
+        return Err(anyhow!("checksum mismatch"));
+    }
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAYLOAD: &[u8] = b"not really a proving key";
+
+    #[test]
+    fn round_trip() {
+        let bytes = encode(3, 1 << 13, PAYLOAD);
+        assert_eq!(decode(&bytes, 3, 1 << 13).unwrap(), PAYLOAD);
+    }
+
+    #[test]
+    fn refuses_another_network_or_degree() {
+        let bytes = encode(3, 1 << 13, PAYLOAD);
+        assert!(decode(&bytes, 4, 1 << 13).is_err());
+        assert!(decode(&bytes, 3, 1 << 14).is_err());
+    }
+
+    #[test]
+    fn refuses_another_layout() {
+        let mut bytes = encode(3, 1 << 13, PAYLOAD);
+        bytes[..MAGIC.len()].copy_from_slice(b"ALEOPK01");
+        assert!(decode(&bytes, 3, 1 << 13).is_err());
+    }
+
+    #[test]
+    fn refuses_a_corrupted_key() {
+        let mut bytes = encode(3, 1 << 13, PAYLOAD);
+        *bytes.last_mut().unwrap() ^= 1;
+        assert!(decode(&bytes, 3, 1 << 13).is_err());
+
+        let mut bytes = encode(3, 1 << 13, PAYLOAD);
+        bytes[HEADER_LEN - 1] ^= 1;
+        assert!(decode(&bytes, 3, 1 << 13).is_err());
+    }
+
+    #[test]
+    fn refuses_a_truncated_file() {
+        let bytes = encode(3, 1 << 13, PAYLOAD);
+        for len in 0..HEADER_LEN {
+            assert!(decode(&bytes[..len], 3, 1 << 13).is_err());
+        }
+        assert!(decode(&bytes[..bytes.len() - 1], 3, 1 << 13).is_err());
+    }
+}
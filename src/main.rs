@@ -1,24 +1,64 @@
 extern crate core;
 
+mod bench;
+mod capture;
 #[forbid(unsafe_code)]
 mod client;
+mod engine;
+mod files;
+mod health;
+mod identity;
+mod keycache;
+mod nonce;
+mod outbound;
+mod peers;
+mod pool;
 mod prover;
+mod proxy;
+mod resolve;
+mod rest;
+mod state;
+#[cfg(test)]
+mod testing;
+mod tracker;
 
 use gethostname::gethostname;
 
-use std::{net::ToSocketAddrs, sync::Arc};
+use std::{path::PathBuf, str::FromStr, sync::Arc, time::Duration};
 
-use clap::Parser;
-use snarkvm::{console::account::address::Address, prelude::Testnet3};
+use clap::{Parser, Subcommand, ValueEnum};
+use snarkvm::{
+    console::account::address::Address,
+    prelude::{Network, Testnet3},
+};
 
 use tracing::{debug, error, info};
 use tracing_subscriber::layer::SubscriberExt;
 
 use crate::{
-    client::{report, start, Client},
+    capture::Recorder,
+    client::{report, start, BeaconConfig, BeaconSource, Client, ReplaySource, WorkSource},
+    nonce::{FleetSlot, NonceSpace},
+    pool::PoolSource,
     prover::Prover,
+    proxy::Proxy,
+    resolve::BeaconEntry,
+    rest::RestSource,
 };
 
+const DEFAULT_BEACONS: [&str; 10] = [
+    "164.92.111.59:4133",
+    "159.223.204.96:4133",
+    "167.71.219.176:4133",
+    "157.245.205.209:4133",
+    "134.122.95.106:4133",
+    "161.35.24.55:4133",
+    "138.68.103.139:4133",
+    "207.154.215.49:4133",
+    "46.101.114.158:4133",
+    "138.197.190.94:4133",
+];
+
 #[derive(Debug, Parser)]
 #[clap(name = "prover", about = "Standalone prover.")]
 struct Opt {
@@ -29,11 +69,89 @@ struct Opt {
     #[clap(verbatim_doc_comment)]
     /// address
     #[clap(short = 'a', long = "address")]
-    address: Option<Address<Testnet3>>,
+    address: Option<String>,
 
-    /// Beacon node address
+    /// Network to prove for
+    #[clap(short = 'n', long = "network", value_enum, default_value = "testnet3")]
+    network: NetworkName,
+
+    /// Beacon node address, host:port, srv:_aleo._tcp.example.com or unix:/path with an
+    /// optional weight ("host:port 3"). Hostnames and SRV records are resolved again on every
+    /// connection. Can be repeated, replaces the default beacons
     #[clap(short = 'b', long = "beacon")]
-    beacon: Option<String>,
+    beacon: Vec<BeaconEntry>,
+
+    /// Beacon list file, one host:port per line with an optional weight
+    #[clap(long = "beacon-file")]
+    beacon_file: Option<PathBuf>,
+
+    /// Keep the default beacons when --beacon, --beacon-file or --seeds are given
+    #[clap(long = "add-default-beacons")]
+    add_default_beacons: bool,
+
+    /// Seed list of beacons, a file or http(s) URL with one host:port per line. Reloaded
+    /// periodically
+    #[clap(short = 's', long = "seeds")]
+    seeds: Option<String>,
+
+    /// Number of beacon connections to keep open at once, defaults to 1
+    #[clap(short = 'c', long = "connections")]
+    connections: Option<u8>,
+
+    /// Seconds allowed for the beacon handshake, defaults to 10
+    #[clap(long = "handshake-timeout", value_parser = clap::value_parser!(u64).range(1..))]
+    handshake_timeout: Option<u64>,
+
+    /// Seconds of beacon silence before reconnecting, defaults to 60
+    #[clap(long = "idle-timeout", value_parser = clap::value_parser!(u64).range(1..))]
+    idle_timeout: Option<u64>,
+
+    /// Seconds between pings to the beacon, defaults to 15
+    #[clap(long = "ping-interval", value_parser = clap::value_parser!(u64).range(1..))]
+    ping_interval: Option<u64>,
+
+    /// Only accept beacons signing with this address, can be repeated
+    #[clap(long = "beacon-address")]
+    beacon_addresses: Vec<String>,
+
+    /// Peer identity key file, created on first use. Keeps the node identity seen by beacons
+    /// stable across restarts
+    #[clap(long = "identity")]
+    identity: Option<PathBuf>,
+
+    /// Proxy for beacon, pool and reporting traffic, socks5://[user:pass@]host:port or
+    /// http://[user:pass@]host:port
+    #[clap(short = 'x', long = "proxy")]
+    proxy: Option<Proxy>,
+
+    /// snarkOS node REST API, e.g. http://127.0.0.1:3033, used to check whether solutions made
+    /// it into blocks, and to get work and submit solutions while no beacon is ready
+    #[clap(long = "node-api")]
+    node_api: Option<String>,
+
+    /// Get work from and submit solutions to --node-api only, without beacons
+    #[clap(long = "rest")]
+    rest: bool,
+
+    /// File to keep discovered beacons in across restarts
+    #[clap(long = "peer-cache")]
+    peer_cache: Option<PathBuf>,
+
+    /// Record every beacon message to this capture file
+    #[clap(long = "record")]
+    record: Option<PathBuf>,
+
+    /// Replay a capture file made with --record instead of connecting to beacons
+    #[clap(long = "replay")]
+    replay: Option<PathBuf>,
+
+    /// Replay speed, 2 replays twice as fast as recorded and 0 without delays. Defaults to 1
+    #[clap(long = "replay-speed")]
+    replay_speed: Option<f64>,
+
+    /// Mining pool address, mine through a stratum pool instead of a beacon
+    #[clap(short = 'p', long = "pool")]
+    pool: Option<String>,
 
     /// Number of threads, defaults to number of CPU threads
     #[clap(short = 't', long = "threads")]
@@ -58,6 +176,76 @@ struct Opt {
     /// worker, belong to user, can statistics by user and worker
     #[clap(short = 'w', long = "worker")]
     worker: Option<String>,
+
+    /// Seed for nonce allocation, the same seed and fleet slot try the same nonces again.
+    /// Random if not given
+    #[clap(long = "nonce-seed")]
+    nonce_seed: Option<u64>,
+
+    /// Cache file for the trimmed coinbase proving key, defaults to
+    /// ~/.aleo-prover/coinbase-<network id>.key
+    #[clap(long = "key-cache")]
+    key_cache: Option<PathBuf>,
+
+    /// Always trim the coinbase proving key from the SRS, without reading or writing the cache
+    #[clap(long = "no-key-cache")]
+    no_key_cache: bool,
+
+    /// This machine's slot in a fleet sharing one address and nonce seed, "<index>/<size>".
+    /// Every slot gets its own part of the nonce space
+    #[clap(long = "fleet", default_value = "0/1")]
+    fleet: FleetSlot,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+/// Networks the prover can run on. A new network needs a variant here and an arm in `main`.
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum NetworkName {
+    Testnet3,
+}
+
+impl NetworkName {
+    /// Name of the network in node REST API paths.
+    fn as_str(&self) -> &'static str {
+        match self {
+            NetworkName::Testnet3 => "testnet3",
+        }
+    }
+
+    fn default_beacons(&self) -> &'static [&'static str] {
+        match self {
+            NetworkName::Testnet3 => &DEFAULT_BEACONS,
+        }
+    }
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Manage the peer identity key file
+    #[clap(subcommand)]
+    Identity(IdentityCommand),
+    /// Compare proving throughput of the proving engine with a blocking task per attempt
+    Bench {
+        /// Seconds to run each way for
+        #[clap(long = "seconds", default_value = "60")]
+        seconds: u64,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum IdentityCommand {
+    /// Create a new peer identity key file
+    New {
+        /// Path of the key file
+        path: PathBuf,
+    },
+    /// Replace the key in an existing peer identity key file
+    Rotate {
+        /// Path of the key file
+        path: PathBuf,
+    },
 }
 
 #[tokio::main]
@@ -74,7 +262,7 @@ async fn main() {
     let subscriber = tracing_subscriber::fmt::Subscriber::builder()
         .with_max_level(tracing_level)
         .finish();
-    if let Some(log) = opt.log {
+    if let Some(log) = &opt.log {
         let file = std::fs::File::create(log).unwrap();
         let file = tracing_subscriber::fmt::layer()
             .with_writer(file)
@@ -86,39 +274,110 @@ async fn main() {
             .expect("unable to set global default subscriber");
     }
 
-    let beacons = if opt.beacon.is_none() {
-        [
-            "164.92.111.59:4133",
-            "159.223.204.96:4133",
-            "167.71.219.176:4133",
-            "157.245.205.209:4133",
-            "134.122.95.106:4133",
-            "161.35.24.55:4133",
-            "138.68.103.139:4133",
-            "207.154.215.49:4133",
-            "46.101.114.158:4133",
-            "138.197.190.94:4133",
-        ]
-        .map(|s| s.to_string())
-        .to_vec()
-    } else {
-        vec![opt.beacon.unwrap()]
+    match opt.network {
+        NetworkName::Testnet3 => run::<Testnet3>(opt).await,
+    }
+}
+
+async fn run<N: Network>(opt: Opt) {
+    let key_cache = match opt.no_key_cache {
+        true => None,
+        false => opt.key_cache.clone().or_else(keycache::default_path::<N>),
     };
+
+    if let Some(command) = opt.command {
+        let result = match command {
+            Command::Identity(IdentityCommand::New { path }) => identity::create::<N>(&path, false),
+            Command::Identity(IdentityCommand::Rotate { path }) => {
+                identity::create::<N>(&path, true)
+            }
+            Command::Bench { seconds } => {
+                bench::run::<N>(
+                    opt.threads.unwrap_or(num_cpus::get() as u16),
+                    opt.thread_pool_size.unwrap_or(4),
+                    key_cache.as_deref(),
+                    Duration::from_secs(seconds),
+                )
+                .await
+            }
+        };
+        if let Err(e) = result {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     if opt.address.is_none() {
         error!("Prover address is required!");
         std::process::exit(1);
     }
-    let address = opt.address.unwrap();
+    let address = match Address::<N>::from_str(&opt.address.unwrap()) {
+        Ok(address) => address,
+        Err(e) => {
+            error!("Invalid prover address: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let mut beacon_addresses = Vec::new();
+    for beacon_address in &opt.beacon_addresses {
+        match Address::<N>::from_str(beacon_address) {
+            Ok(address) => beacon_addresses.push(address),
+            Err(e) => {
+                error!("Invalid beacon address {}: {}", beacon_address, e);
+                std::process::exit(1);
+            }
+        }
+    }
 
-    beacons
-        .iter()
-        .map(|s| {
-            if let Err(e) = s.to_socket_addrs() {
-                error!("Invalid beacon node address: {}", e);
+    let mut beacons = opt.beacon;
+    if let Some(path) = &opt.beacon_file {
+        match resolve::read_list(path) {
+            Ok(entries) => beacons.extend(entries),
+            Err(e) => {
+                error!("Invalid beacon file: {}", e);
                 std::process::exit(1);
             }
-        })
-        .for_each(drop);
+        }
+    }
+    if let Some(seeds) = &opt.seeds {
+        match resolve::load_seeds(seeds, opt.proxy.as_ref()).await {
+            Ok(entries) => {
+                info!("Loaded {} beacons from seed list", entries.len());
+                beacons.extend(entries);
+            }
+            Err(e) => {
+                error!("Unable to load seed list {}: {}", seeds, e);
+                std::process::exit(1);
+            }
+        }
+    }
+    if beacons.is_empty() || opt.add_default_beacons {
+        beacons.extend(
+            opt.network
+                .default_beacons()
+                .iter()
+                .map(|server| BeaconEntry {
+                    server: server.to_string(),
+                    weight: 1,
+                }),
+        );
+    }
+    // The first occurrence of a beacon wins, so explicit entries override the defaults.
+    let mut unique: Vec<BeaconEntry> = Vec::new();
+    for beacon in beacons {
+        if !unique.iter().any(|b| b.server == beacon.server) {
+            unique.push(beacon);
+        }
+    }
+    let beacons = unique;
+
+    if let Some(pool) = &opt.pool {
+        if let Err(e) = resolve::validate(pool) {
+            error!("Invalid pool address: {}", e);
+            std::process::exit(1);
+        }
+    }
 
     let threads = opt.threads.unwrap_or(num_cpus::get() as u16);
     let thread_pool_size = opt.thread_pool_size.unwrap_or(4);
@@ -142,6 +401,17 @@ async fn main() {
         }
     }
 
+    let identity = match opt.identity {
+        Some(path) => match identity::load_or_create::<N>(&path) {
+            Ok(account) => Some(account),
+            Err(e) => {
+                error!("Unable to load peer identity: {}", e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
     let worker = if opt.worker.is_none() {
         gethostname().into_string().unwrap()
     } else {
@@ -150,20 +420,111 @@ async fn main() {
 
     info!("Starting prover");
 
-    let client = Client::init(address, beacons, worker);
+    if let Some(proxy) = &opt.proxy {
+        // HTTP traffic must not bypass the proxy either, so refuse to start without it.
+        if let Err(e) = proxy.to_reqwest() {
+            error!("Invalid proxy {} for HTTP requests: {}", proxy, e);
+            std::process::exit(1);
+        }
+        info!("Using proxy {}", proxy);
+    }
+    let client = Client::init(address, opt.network.as_str(), beacons, worker, opt.proxy);
+
+    let nonces = NonceSpace::new(opt.nonce_seed.unwrap_or_else(rand::random), opt.fleet);
+    let prover: Arc<Prover<N>> = match Prover::init(
+        threads,
+        thread_pool_size,
+        client.clone(),
+        cuda,
+        cuda_jobs,
+        nonces,
+        key_cache,
+    )
+    .await
+    {
+        Ok(prover) => prover,
+        Err(e) => {
+            error!("Unable to initialize prover: {}", e);
+            std::process::exit(1);
+        }
+    };
+    debug!("Prover initialized");
 
-    let prover: Arc<Prover> =
-        match Prover::init(threads, thread_pool_size, client.clone(), cuda, cuda_jobs).await {
-            Ok(prover) => prover,
+    let recorder = match &opt.record {
+        Some(path) => match Recorder::create(path) {
+            Ok(recorder) => Some(Arc::new(recorder)),
             Err(e) => {
-                error!("Unable to initialize prover: {}", e);
+                error!("{}", e);
                 std::process::exit(1);
             }
-        };
-    debug!("Prover initialized");
+        },
+        None => None,
+    };
 
-    start(prover.clone(), client.clone());
+    let source: Arc<dyn WorkSource<N>> = match (opt.replay, opt.pool, opt.rest) {
+        (Some(replay), _, _) => Arc::new(ReplaySource::<N>::new(
+            replay,
+            opt.replay_speed.unwrap_or(1.0).max(0.0),
+        )),
+        (None, Some(pool), _) => Arc::new(PoolSource::new(pool)),
+        (None, None, true) => match opt.node_api {
+            Some(node_api) => Arc::new(RestSource::new(node_api)),
+            None => {
+                error!("--rest needs --node-api");
+                std::process::exit(1);
+            }
+        },
+        (None, None, false) => {
+            let default = BeaconConfig::<N>::default();
+            Arc::new(BeaconSource::new(BeaconConfig {
+                connections: opt
+                    .connections
+                    .map(|c| c as usize)
+                    .unwrap_or(default.connections),
+                handshake_timeout: opt
+                    .handshake_timeout
+                    .map(Duration::from_secs)
+                    .unwrap_or(default.handshake_timeout),
+                idle_timeout: opt
+                    .idle_timeout
+                    .map(Duration::from_secs)
+                    .unwrap_or(default.idle_timeout),
+                ping_interval: opt
+                    .ping_interval
+                    .map(Duration::from_secs)
+                    .unwrap_or(default.ping_interval),
+                beacon_addresses,
+                identity,
+                seeds: opt.seeds,
+                node_api: opt.node_api,
+                recorder,
+                peer_cache: opt.peer_cache,
+            }))
+        }
+    };
+    start(prover.clone(), client.clone(), source);
     report(prover.clone(), client.clone());
 
     std::future::pending::<()>().await;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_intervals_are_rejected() {
+        for flag in ["--handshake-timeout", "--idle-timeout", "--ping-interval"] {
+            assert!(
+                Opt::try_parse_from(["prover", flag, "0"]).is_err(),
+                "{}",
+                flag
+            );
+            assert!(
+                Opt::try_parse_from(["prover", flag, "1"]).is_ok(),
+                "{}",
+                flag
+            );
+        }
+    }
+}
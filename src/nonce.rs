@@ -0,0 +1,211 @@
+use std::{
+    fmt,
+    str::FromStr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use anyhow::{anyhow, Result};
+
+/// This machine's place in a fleet of provers sharing one address: `<index>/<size>`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FleetSlot {
+    pub index: u32,
+    pub size: u32,
+}
+
+impl Default for FleetSlot {
+    fn default() -> Self {
+        Self { index: 0, size: 1 }
+    }
+}
+
+impl FromStr for FleetSlot {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (index, size) = s
+            .split_once('/')
+            .ok_or_else(|| anyhow!("expected <index>/<size>, got {}", s))?;
+        let index = index
+            .trim()
+            .parse::<u32>()
+            .map_err(|_| anyhow!("invalid fleet index {}", index))?;
+        let size = size
+            .trim()
+            .parse::<u32>()
+            .map_err(|_| anyhow!("invalid fleet size {}", size))?;
+        if size == 0 || index >= size {
+            return Err(anyhow!(
+                "fleet index {} is not below fleet size {}",
+                index,
+                size
+            ));
+        }
+        Ok(Self { index, size })
+    }
+}
+
+impl fmt::Display for FleetSlot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.index, self.size)
+    }
+}
+
+/// Splits the nonce space of an epoch between the machines of a fleet and the thread pools of
+/// each machine.
+///
+/// The space is cut into `fleet size * pools` equal slices and every pool walks its own slice
+/// in order, so no two pools anywhere in the fleet try the same nonce. All slices are shifted
+/// by an offset derived from the seed and the epoch, which keeps the nonces reproducible from
+/// the seed without every fleet starting at zero.
+#[derive(Clone, Copy, Debug)]
+pub struct NonceSpace {
+    seed: u64,
+    fleet: FleetSlot,
+}
+
+impl NonceSpace {
+    pub fn new(seed: u64, fleet: FleetSlot) -> Self {
+        Self { seed, fleet }
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn fleet(&self) -> FleetSlot {
+        self.fleet
+    }
+
+    /// The slice of pool `pool` out of `pools` for `epoch`.
+    pub fn range(&self, epoch: u32, pool: u32, pools: u32) -> NonceRange {
+        let pools = pools.max(1) as u64;
+        let slices = self.fleet.size as u64 * pools;
+        let len = u64::MAX / slices;
+        let slot = self.fleet.index as u64 * pools + pool as u64;
+        let offset = splitmix64(self.seed ^ epoch as u64);
+        NonceRange {
+            start: offset.wrapping_add(slot * len),
+            len,
+            next: AtomicU64::new(0),
+        }
+    }
+}
+
+/// One pool's slice of the nonce space, handed out in order.
+pub struct NonceRange {
+    start: u64,
+    len: u64,
+    next: AtomicU64,
+}
+
+impl NonceRange {
+    /// The next untried nonce, `None` once the slice is used up.
+    pub fn next_nonce(&self) -> Option<u64> {
+        let index = self.next.fetch_add(1, Ordering::SeqCst);
+        if index >= self.len {
+            return None;
+        }
+        Some(self.start.wrapping_add(index))
+    }
+
+    /// Nonces handed out so far.
+    pub fn tried(&self) -> u64 {
+        self.next.load(Ordering::SeqCst).min(self.len)
+    }
+
+    /// Share of the slice handed out so far.
+    pub fn progress(&self) -> f64 {
+        self.tried() as f64 / self.len as f64
+    }
+}
+
+/// The SplitMix64 finalizer, a bijection on u64 that spreads nearby seeds far apart.
+fn splitmix64(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    #[test]
+    fn ranges_are_disjoint_across_pools_and_fleet() {
+        let (size, pools) = (3, 4);
+        let ranges = (0..size)
+            .flat_map(|index| {
+                let space = NonceSpace::new(42, FleetSlot { index, size });
+                (0..pools).map(move |pool| space.range(7, pool, pools))
+            })
+            .collect::<Vec<_>>();
+
+        // Measured from the first range, every range must end before the next one starts
+        // without wrapping around to the first.
+        let origin = ranges[0].start;
+        let mut starts = ranges
+            .iter()
+            .map(|range| range.start.wrapping_sub(origin))
+            .collect::<Vec<_>>();
+        starts.sort_unstable();
+        let len = ranges[0].len;
+        assert!(ranges.iter().all(|range| range.len == len));
+        for pair in starts.windows(2) {
+            assert!(pair[0] + len <= pair[1]);
+        }
+        assert!(starts.last().unwrap().checked_add(len).is_some());
+
+        let mut seen = HashSet::new();
+        for range in &ranges {
+            for _ in 0..100 {
+                assert!(seen.insert(range.next_nonce().unwrap()));
+            }
+        }
+    }
+
+    #[test]
+    fn same_seed_and_epoch_give_the_same_nonces() {
+        let nonces = |seed, epoch| {
+            let range = NonceSpace::new(seed, FleetSlot::default()).range(epoch, 1, 2);
+            (0..10)
+                .map(|_| range.next_nonce().unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(nonces(1, 5), nonces(1, 5));
+        assert_ne!(nonces(1, 5), nonces(1, 6));
+        assert_ne!(nonces(1, 5), nonces(2, 5));
+    }
+
+    #[test]
+    fn range_ends_at_its_length() {
+        let range = NonceRange {
+            start: u64::MAX - 1,
+            len: 3,
+            next: AtomicU64::new(0),
+        };
+        assert_eq!(range.next_nonce(), Some(u64::MAX - 1));
+        assert_eq!(range.next_nonce(), Some(u64::MAX));
+        assert_eq!(range.next_nonce(), Some(0));
+        assert_eq!(range.next_nonce(), None);
+        assert_eq!(range.next_nonce(), None);
+        assert_eq!(range.tried(), 3);
+        assert_eq!(range.progress(), 1.0);
+    }
+
+    #[test]
+    fn fleet_slot_parsing() {
+        assert_eq!(
+            "2/5".parse::<FleetSlot>().unwrap(),
+            FleetSlot { index: 2, size: 5 }
+        );
+        assert_eq!("0 / 1".parse::<FleetSlot>().unwrap(), FleetSlot::default());
+        assert_eq!(FleetSlot { index: 2, size: 5 }.to_string(), "2/5");
+        for bad in ["", "1", "a/2", "1/b", "-1/2", "2/2", "3/2", "0/0", "1/2/3"] {
+            assert!(bad.parse::<FleetSlot>().is_err(), "{}", bad);
+        }
+    }
+}
@@ -0,0 +1,229 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::Mutex,
+};
+
+use snarkvm::prelude::Network;
+use tokio::sync::Notify;
+use tracing::debug;
+
+type Message<N> = snarkos_node_messages::Message<N>;
+
+/// A message in the outbound queue. Solutions carry the epoch they were found for, so they
+/// can be dropped once that epoch is over.
+#[derive(Clone)]
+pub struct Outbound<N: Network> {
+    pub message: Message<N>,
+    pub epoch: Option<u32>,
+}
+
+impl<N: Network> From<Message<N>> for Outbound<N> {
+    fn from(message: Message<N>) -> Self {
+        Self {
+            message,
+            epoch: None,
+        }
+    }
+}
+
+impl<N: Network> Outbound<N> {
+    fn is_urgent(&self) -> bool {
+        matches!(self.message, Message::UnconfirmedSolution(_))
+    }
+}
+
+/// Outbound messages waiting to be sent, solutions first.
+///
+/// Housekeeping messages keep their order behind any pending solution, and a `PuzzleRequest`
+/// is dropped when another one is still waiting, so a burst of requests never delays a
+/// solution.
+pub struct OutboundQueue<N: Network> {
+    queues: Mutex<Queues<N>>,
+    notify: Notify,
+}
+
+struct Queues<N: Network> {
+    urgent: VecDeque<Outbound<N>>,
+    housekeeping: VecDeque<Outbound<N>>,
+}
+
+impl<N: Network> Default for OutboundQueue<N> {
+    fn default() -> Self {
+        Self {
+            queues: Mutex::new(Queues {
+                urgent: VecDeque::new(),
+                housekeeping: VecDeque::new(),
+            }),
+            notify: Notify::new(),
+        }
+    }
+}
+
+impl<N: Network> OutboundQueue<N> {
+    pub fn push(&self, item: Outbound<N>) {
+        {
+            let mut queues = self.queues.lock().unwrap();
+            if item.is_urgent() {
+                queues.urgent.push_back(item);
+            } else if matches!(item.message, Message::PuzzleRequest(_))
+                && queues
+                    .housekeeping
+                    .iter()
+                    .any(|pending| matches!(pending.message, Message::PuzzleRequest(_)))
+            {
+                debug!("A puzzle request is already pending, skipping");
+                return;
+            } else {
+                queues.housekeeping.push_back(item);
+            }
+        }
+        self.notify.notify_one();
+    }
+
+    /// Waits for the next message. Safe to cancel, nothing is taken until it returns.
+    pub async fn pop(&self) -> Outbound<N> {
+        loop {
+            {
+                let mut queues = self.queues.lock().unwrap();
+                if let Some(item) = queues
+                    .urgent
+                    .pop_front()
+                    .or_else(|| queues.housekeeping.pop_front())
+                {
+                    return item;
+                }
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    /// Drops every queued solution not found for `epoch`, returning how many were dropped.
+    pub fn retain_epoch(&self, epoch: u32) -> usize {
+        let mut queues = self.queues.lock().unwrap();
+        let before = queues.urgent.len();
+        queues
+            .urgent
+            .retain(|item| item.epoch.map_or(true, |item_epoch| item_epoch == epoch));
+        before - queues.urgent.len()
+    }
+}
+
+/// Messages written to the wire, by type.
+#[derive(Default)]
+pub struct SendCounters {
+    counts: Mutex<BTreeMap<String, u64>>,
+}
+
+impl SendCounters {
+    pub fn record(&self, name: &str) {
+        *self
+            .counts
+            .lock()
+            .unwrap()
+            .entry(name.to_string())
+            .or_default() += 1;
+    }
+
+    pub fn snapshot(&self) -> Vec<(String, u64)> {
+        self.counts
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, count)| (name.clone(), *count))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use snarkos_node_messages::{Data, NodeType, Ping, PuzzleRequest, UnconfirmedSolution};
+    use snarkvm::prelude::Testnet3;
+
+    use super::*;
+    use crate::testing;
+
+    fn solution(epoch: Option<u32>) -> Outbound<Testnet3> {
+        let solution = testing::solution(&mut rand::thread_rng());
+        Outbound {
+            message: Message::UnconfirmedSolution(UnconfirmedSolution {
+                puzzle_commitment: solution.commitment(),
+                solution: Data::Object(solution),
+            }),
+            epoch,
+        }
+    }
+
+    fn ping() -> Outbound<Testnet3> {
+        Message::Ping(Ping {
+            version: Message::<Testnet3>::VERSION,
+            node_type: NodeType::Prover,
+            block_locators: None,
+        })
+        .into()
+    }
+
+    fn puzzle_request() -> Outbound<Testnet3> {
+        Message::PuzzleRequest(PuzzleRequest {}).into()
+    }
+
+    fn epoch_of(item: &Outbound<Testnet3>) -> Option<u32> {
+        assert!(item.is_urgent(), "expected a solution");
+        item.epoch
+    }
+
+    #[tokio::test]
+    async fn solutions_go_before_housekeeping() {
+        let queue = OutboundQueue::default();
+        queue.push(ping());
+        queue.push(puzzle_request());
+        queue.push(solution(Some(1)));
+        queue.push(solution(Some(2)));
+
+        assert_eq!(epoch_of(&queue.pop().await), Some(1));
+        assert_eq!(epoch_of(&queue.pop().await), Some(2));
+        assert!(matches!(queue.pop().await.message, Message::Ping(_)));
+        assert!(matches!(
+            queue.pop().await.message,
+            Message::PuzzleRequest(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn pending_puzzle_requests_collapse() {
+        let queue = OutboundQueue::default();
+        queue.push(puzzle_request());
+        queue.push(puzzle_request());
+        queue.push(ping());
+        queue.push(puzzle_request());
+
+        assert!(matches!(
+            queue.pop().await.message,
+            Message::PuzzleRequest(_)
+        ));
+        assert!(matches!(queue.pop().await.message, Message::Ping(_)));
+        assert!(queue.queues.lock().unwrap().housekeeping.is_empty());
+
+        // Once the pending one is sent, the next request is queued again.
+        queue.push(puzzle_request());
+        assert!(matches!(
+            queue.pop().await.message,
+            Message::PuzzleRequest(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn retain_epoch_drops_only_other_epochs_solutions() {
+        let queue = OutboundQueue::default();
+        queue.push(solution(Some(1)));
+        queue.push(solution(None));
+        queue.push(ping());
+        queue.push(solution(Some(2)));
+        queue.push(solution(Some(1)));
+
+        assert_eq!(queue.retain_epoch(2), 2);
+        assert_eq!(epoch_of(&queue.pop().await), None);
+        assert_eq!(epoch_of(&queue.pop().await), Some(2));
+        assert!(matches!(queue.pop().await.message, Message::Ping(_)));
+        assert_eq!(queue.retain_epoch(2), 0);
+    }
+}
@@ -0,0 +1,154 @@
+use std::{
+    fs,
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+use crate::{files, resolve::BeaconEntry};
+
+/// Peers that have not worked for this long are forgotten.
+const MAX_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+/// Peers kept at most, the best scored ones win.
+const MAX_PEERS: usize = 256;
+
+#[derive(Clone, Serialize, Deserialize)]
+struct CachedPeer {
+    server: String,
+    successes: u32,
+    failures: u32,
+    /// Unix time of the last success, or of the first failure if it never worked.
+    last_seen: u64,
+    /// Unix time of the last attempt.
+    last_tried: u64,
+}
+
+impl CachedPeer {
+    fn score(&self) -> i64 {
+        self.successes as i64 * 2 - self.failures as i64
+    }
+}
+
+/// Nodes we learned about and how well they worked as beacons.
+///
+/// Saved to `path` when one is given, so a prover restarted with a stale seed list still knows
+/// nodes that worked before.
+pub struct PeerCache {
+    path: Option<PathBuf>,
+    peers: Mutex<Vec<CachedPeer>>,
+}
+
+impl PeerCache {
+    pub fn load(path: Option<PathBuf>) -> Self {
+        let peers = match &path {
+            Some(path) if path.exists() => match fs::read_to_string(path)
+                .map_err(|e| e.to_string())
+                .and_then(|text| serde_json::from_str(&text).map_err(|e| e.to_string()))
+            {
+                Ok(peers) => peers,
+                Err(e) => {
+                    warn!("Ignoring peer cache {}: {}", path.display(), e);
+                    Vec::new()
+                }
+            },
+            _ => Vec::new(),
+        };
+        if let Some(path) = &path {
+            info!("Loaded {} peers from {}", peers.len(), path.display());
+        }
+        Self {
+            path,
+            peers: Mutex::new(peers),
+        }
+    }
+
+    /// Peers that worked more often than not, best first.
+    pub fn beacons(&self) -> Vec<BeaconEntry> {
+        let mut peers = self
+            .peers
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|peer| peer.score() > 0)
+            .cloned()
+            .collect::<Vec<_>>();
+        peers.sort_by_key(|peer| -peer.score());
+        peers
+            .into_iter()
+            .map(|peer| BeaconEntry {
+                server: peer.server,
+                weight: 1,
+            })
+            .collect()
+    }
+
+    /// Whether `server` was tried within `interval`.
+    pub fn tried_within(&self, server: &str, interval: Duration) -> bool {
+        let since = now().saturating_sub(interval.as_secs());
+        self.peers
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|peer| peer.server == server && peer.last_tried >= since)
+    }
+
+    pub fn record(&self, server: &str, success: bool) {
+        let now = now();
+        let mut peers = self.peers.lock().unwrap();
+        let peer = match peers.iter().position(|peer| peer.server == server) {
+            Some(index) => &mut peers[index],
+            None => {
+                peers.push(CachedPeer {
+                    server: server.to_string(),
+                    successes: 0,
+                    failures: 0,
+                    last_seen: now,
+                    last_tried: now,
+                });
+                peers.last_mut().unwrap()
+            }
+        };
+        peer.last_tried = now;
+        if success {
+            peer.successes += 1;
+            peer.last_seen = now;
+        } else {
+            peer.failures += 1;
+        }
+    }
+
+    /// Drops stale peers and writes the cache out, if it has a path.
+    pub fn save(&self) {
+        let peers = {
+            let mut peers = self.peers.lock().unwrap();
+            let since = now().saturating_sub(MAX_AGE.as_secs());
+            peers.retain(|peer| peer.last_seen >= since);
+            peers.sort_by_key(|peer| -peer.score());
+            peers.truncate(MAX_PEERS);
+            peers.clone()
+        };
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+        let result = serde_json::to_string_pretty(&peers)
+            .map_err(|e| e.to_string())
+            .and_then(|json| {
+                files::write_atomic(path, json.as_bytes(), None).map_err(|e| e.to_string())
+            });
+        match result {
+            Ok(()) => debug!("Saved {} peers to {}", peers.len(), path.display()),
+            Err(e) => warn!("Unable to save peer cache {}: {}", path.display(), e),
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
@@ -0,0 +1,485 @@
+use std::{io, sync::Arc, time::Duration};
+
+use anyhow::{anyhow, Result};
+use bytes::BytesMut;
+use futures::future::BoxFuture;
+use futures_util::sink::SinkExt;
+use json_rpc_types::{Error, Id, Request, Response, Version};
+use serde_json::{json, Value};
+use snarkos_node_messages::Data;
+use snarkvm::{
+    prelude::{FromBytes, Network, ToBytes},
+    synthesizer::EpochChallenge,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::mpsc,
+    time::sleep,
+};
+use tokio_stream::StreamExt;
+use tokio_util::codec::{Decoder, Encoder, Framed, LinesCodec};
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    client::{Client, WorkSource},
+    outbound::Outbound,
+    prover::{Prover, ProverEvent},
+    resolve,
+};
+
+type Message<N> = snarkos_node_messages::Message<N>;
+
+const PROTOCOL_VERSION: &str = "AleoStratum/1.0.0";
+
+/// Messages of the stratum-style pool protocol.
+///
+/// Requests and responses are JSON-RPC 2.0 objects, one per line.
+#[derive(Debug)]
+pub enum StratumMessage {
+    /// (id, user_agent, protocol_version, session_id)
+    Subscribe(Id, String, String, Option<String>),
+    /// (id, address, worker)
+    Authorize(Id, String, String),
+    /// (job_id, epoch_challenge, share_target)
+    Notify(String, String, u64),
+    /// (id, worker, job_id, solution)
+    Submit(Id, String, String, String),
+    /// (id, result, error)
+    Response(Id, Option<Value>, Option<Error<Value>>),
+}
+
+impl StratumMessage {
+    pub fn name(&self) -> &'static str {
+        match self {
+            StratumMessage::Subscribe(..) => "mining.subscribe",
+            StratumMessage::Authorize(..) => "mining.authorize",
+            StratumMessage::Notify(..) => "mining.notify",
+            StratumMessage::Submit(..) => "mining.submit",
+            StratumMessage::Response(..) => "response",
+        }
+    }
+}
+
+pub struct StratumCodec {
+    codec: LinesCodec,
+}
+
+impl Default for StratumCodec {
+    fn default() -> Self {
+        Self {
+            codec: LinesCodec::new_with_max_length(4096),
+        }
+    }
+}
+
+impl Encoder<StratumMessage> for StratumCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: StratumMessage, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let method = item.name();
+        let line = match item {
+            StratumMessage::Subscribe(id, user_agent, protocol_version, session_id) => {
+                serde_json::to_string(&Request {
+                    jsonrpc: Version::V2,
+                    method,
+                    params: Some(json!([user_agent, protocol_version, session_id])),
+                    id: Some(id),
+                })
+            }
+            StratumMessage::Authorize(id, address, worker) => serde_json::to_string(&Request {
+                jsonrpc: Version::V2,
+                method,
+                params: Some(json!([address, worker])),
+                id: Some(id),
+            }),
+            StratumMessage::Notify(job_id, epoch_challenge, share_target) => {
+                serde_json::to_string(&Request {
+                    jsonrpc: Version::V2,
+                    method,
+                    params: Some(json!([job_id, epoch_challenge, share_target])),
+                    id: None,
+                })
+            }
+            StratumMessage::Submit(id, worker, job_id, solution) => {
+                serde_json::to_string(&Request {
+                    jsonrpc: Version::V2,
+                    method,
+                    params: Some(json!([worker, job_id, solution])),
+                    id: Some(id),
+                })
+            }
+            StratumMessage::Response(id, result, error) => {
+                let response = match error {
+                    Some(error) => Response::<Value, Value>::error(Version::V2, error, Some(id)),
+                    None => Response::result(Version::V2, result.unwrap_or(Value::Null), Some(id)),
+                };
+                serde_json::to_string(&response)
+            }
+        }
+        .map_err(invalid_data)?;
+        self.codec.encode(line, dst).map_err(invalid_data)
+    }
+}
+
+fn invalid_data<E: ToString>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+fn param<T: serde::de::DeserializeOwned>(params: &[Value], index: usize) -> io::Result<T> {
+    let value = params
+        .get(index)
+        .cloned()
+        .ok_or_else(|| invalid_data(format!("missing parameter {}", index)))?;
+    serde_json::from_value(value).map_err(invalid_data)
+}
+
+impl Decoder for StratumCodec {
+    type Item = StratumMessage;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let line = match self.codec.decode(src).map_err(invalid_data)? {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+        let value = serde_json::from_str::<Value>(&line).map_err(invalid_data)?;
+        if value.get("method").is_none() {
+            let response =
+                serde_json::from_value::<Response<Value, Value>>(value).map_err(invalid_data)?;
+            let id = response
+                .id
+                .ok_or_else(|| invalid_data("response without id"))?;
+            return Ok(Some(match response.payload {
+                Ok(result) => StratumMessage::Response(id, Some(result), None),
+                Err(error) => StratumMessage::Response(id, None, Some(error)),
+            }));
+        }
+        let request = serde_json::from_value::<Request<Vec<Value>>>(value).map_err(invalid_data)?;
+        let params = request.params.unwrap_or_default();
+        let message = match request.method.as_str() {
+            "mining.subscribe" => StratumMessage::Subscribe(
+                request
+                    .id
+                    .ok_or_else(|| invalid_data("subscribe without id"))?,
+                param(&params, 0)?,
+                param(&params, 1)?,
+                param(&params, 2).unwrap_or_default(),
+            ),
+            "mining.authorize" => StratumMessage::Authorize(
+                request
+                    .id
+                    .ok_or_else(|| invalid_data("authorize without id"))?,
+                param(&params, 0)?,
+                param(&params, 1)?,
+            ),
+            "mining.notify" => {
+                StratumMessage::Notify(param(&params, 0)?, param(&params, 1)?, param(&params, 2)?)
+            }
+            "mining.submit" => StratumMessage::Submit(
+                request
+                    .id
+                    .ok_or_else(|| invalid_data("submit without id"))?,
+                param(&params, 0)?,
+                param(&params, 1)?,
+                param(&params, 2)?,
+            ),
+            method => return Err(invalid_data(format!("unknown method {}", method))),
+        };
+        Ok(Some(message))
+    }
+}
+
+/// Connection to a mining pool speaking the stratum-style JSON-RPC protocol.
+///
+/// The pool hands out work with `mining.notify` and accepts solutions with `mining.submit`;
+/// the submit responses drive the prover's share statistics.
+pub struct PoolSource {
+    server: String,
+}
+
+impl PoolSource {
+    pub fn new(server: String) -> Self {
+        Self { server }
+    }
+}
+
+impl<N: Network> WorkSource<N> for PoolSource {
+    fn name(&self) -> &'static str {
+        "pool"
+    }
+
+    fn run(
+        self: Arc<Self>,
+        prover: Arc<Prover<N>>,
+        client: Arc<Client<N>>,
+    ) -> BoxFuture<'static, ()> {
+        Box::pin(async move {
+            loop {
+                info!("Connecting to pool {}...", self.server);
+                match resolve::connect(client.proxy(), &self.server, Duration::from_secs(5)).await {
+                    Ok(socket) => {
+                        info!("Connected to pool {}", self.server);
+                        if let Err(e) = session(socket, &prover.sender(), &client).await {
+                            error!("Pool session ended: {}", e);
+                        }
+                    }
+                    Err(e) => {
+                        error!("Failed to connect to pool: {}", e);
+                    }
+                }
+                sleep(Duration::from_secs(5)).await;
+            }
+        })
+    }
+}
+
+/// Runs one pool session over `stream` until the pool disconnects or a protocol error occurs.
+/// Work and share results go to `prover`.
+pub async fn session<N, S>(
+    stream: S,
+    prover: &mpsc::Sender<ProverEvent<N>>,
+    client: &Client<N>,
+) -> Result<()>
+where
+    N: Network,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut framed = Framed::new(stream, StratumCodec::default());
+    let mut next_id = 0u64;
+    let mut next_request_id = || {
+        next_id += 1;
+        Id::Num(next_id)
+    };
+
+    let subscribe_id = next_request_id();
+    framed
+        .send(StratumMessage::Subscribe(
+            subscribe_id.clone(),
+            format!("aleo-prover/{}", env!("CARGO_PKG_VERSION")),
+            PROTOCOL_VERSION.to_string(),
+            None,
+        ))
+        .await?;
+    let mut authorize_id = None;
+    let mut authorized = false;
+    let mut job_id: Option<String> = None;
+    let mut job_epoch: Option<u32> = None;
+    let mut pending_submits = Vec::new();
+
+    let outbound = client.outbound();
+    loop {
+        tokio::select! {
+            Outbound { message, epoch } = outbound.pop() => {
+                // Only the job of the current epoch is live, the pool refuses shares for any other.
+                if let (Some(epoch), Some(job_epoch)) = (epoch, job_epoch) {
+                    if epoch != job_epoch {
+                        info!("Dropping stale solution for epoch {}, current epoch is {}", epoch, job_epoch);
+                        continue;
+                    }
+                }
+                let solution = match message {
+                    Message::UnconfirmedSolution(message) => message.solution,
+                    message => {
+                        debug!("Pool does not take {}, dropping", message.name());
+                        continue;
+                    }
+                };
+                let job_id = match (&job_id, authorized) {
+                    (Some(job_id), true) => job_id.clone(),
+                    _ => {
+                        warn!("Not authorized with the pool yet, dropping solution");
+                        continue;
+                    }
+                };
+                let solution = match solution {
+                    Data::Object(solution) => solution.to_bytes_le()?,
+                    Data::Buffer(bytes) => bytes.to_vec(),
+                };
+                let submit_id = next_request_id();
+                pending_submits.push(submit_id.clone());
+                framed
+                    .send(StratumMessage::Submit(submit_id, client.get_worker(), job_id, hex::encode(solution)))
+                    .await?;
+                client.sent().record("mining.submit");
+                info!("Sent solution to pool");
+            }
+            result = framed.next() => match result {
+                Some(Ok(message)) => {
+                    debug!("Received {} from pool", message.name());
+                    match message {
+                        StratumMessage::Response(id, result, error) if id == subscribe_id => {
+                            if let Some(error) = error {
+                                return Err(anyhow!("Pool refused subscription: {}", error.message.as_str()));
+                            }
+                            debug!("Subscribed to pool: {:?}", result);
+                            let request_id = next_request_id();
+                            authorize_id = Some(request_id.clone());
+                            framed
+                                .send(StratumMessage::Authorize(request_id, client.address().to_string(), client.get_worker()))
+                                .await?;
+                        }
+                        StratumMessage::Response(id, result, error) if Some(&id) == authorize_id.as_ref() => {
+                            if error.is_some() || result != Some(Value::Bool(true)) {
+                                return Err(anyhow!("Pool refused authorization for {}", client.address()));
+                            }
+                            info!("Authorized with pool as {}.{}", client.address(), client.get_worker());
+                            authorized = true;
+                        }
+                        StratumMessage::Response(id, result, error) => {
+                            match pending_submits.iter().position(|pending| *pending == id) {
+                                Some(index) => {
+                                    pending_submits.swap_remove(index);
+                                }
+                                None => {
+                                    warn!("Unexpected response from pool: {:?}", id);
+                                    continue;
+                                }
+                            }
+                            let event = match error {
+                                Some(error) => ProverEvent::Result(false, Some(error.message.as_str().to_string())),
+                                None => ProverEvent::Result(result == Some(Value::Bool(true)), None),
+                            };
+                            if let Err(e) = prover.send(event).await {
+                                error!("Error sending share result to prover: {}", e);
+                            }
+                        }
+                        StratumMessage::Notify(new_job_id, epoch_challenge, share_target) => {
+                            let epoch_challenge = EpochChallenge::<N>::from_bytes_le(&hex::decode(epoch_challenge)?)?;
+                            job_id = Some(new_job_id);
+                            job_epoch = Some(epoch_challenge.epoch_number());
+                            if let Err(e) = prover.send(ProverEvent::NewTarget(share_target)).await {
+                                error!("Error sending new target to prover: {}", e);
+                            }
+                            if let Err(e) = prover.send(ProverEvent::NewWork(epoch_challenge.epoch_number(), epoch_challenge, client.address())).await {
+                                error!("Error sending new work to prover: {}", e);
+                            }
+                        }
+                        message => {
+                            debug!("Unhandled message: {}", message.name());
+                        }
+                    }
+                }
+                Some(Err(e)) => {
+                    warn!("Failed to read the message: {:?}", e);
+                }
+                None => {
+                    return Err(anyhow!("Disconnected from pool"));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use snarkos_node_messages::UnconfirmedSolution;
+    use snarkvm::prelude::Testnet3;
+    use tokio::io::duplex;
+
+    use super::*;
+    use crate::testing;
+
+    async fn expect<S: AsyncRead + AsyncWrite + Unpin>(
+        pool: &mut Framed<S, StratumCodec>,
+    ) -> StratumMessage {
+        match pool.next().await {
+            Some(Ok(message)) => message,
+            other => panic!("expected a message from the prover, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn session_subscribes_authorizes_and_submits() {
+        let client = Client::<Testnet3>::init(
+            testing::address(),
+            "testnet3",
+            vec![],
+            "rig".to_string(),
+            None,
+        );
+        let (sender, mut events) = mpsc::channel(16);
+        let (ours, theirs) = duplex(64 * 1024);
+        let session = tokio::spawn({
+            let client = client.clone();
+            async move { session(ours, &sender, &client).await }
+        });
+        let mut pool = Framed::new(theirs, StratumCodec::default());
+
+        let id = match expect(&mut pool).await {
+            StratumMessage::Subscribe(id, _, protocol_version, None) => {
+                assert_eq!(protocol_version, PROTOCOL_VERSION);
+                id
+            }
+            other => panic!("expected mining.subscribe, got {:?}", other),
+        };
+        pool.send(StratumMessage::Response(id, Some(json!([null, "0"])), None))
+            .await
+            .unwrap();
+
+        let id = match expect(&mut pool).await {
+            StratumMessage::Authorize(id, address, worker) => {
+                assert_eq!(address, client.address().to_string());
+                assert_eq!(worker, "rig");
+                id
+            }
+            other => panic!("expected mining.authorize, got {:?}", other),
+        };
+        pool.send(StratumMessage::Response(id, Some(Value::Bool(true)), None))
+            .await
+            .unwrap();
+
+        let epoch_challenge = testing::epoch_challenge(7);
+        pool.send(StratumMessage::Notify(
+            "job-1".to_string(),
+            hex::encode(epoch_challenge.to_bytes_le().unwrap()),
+            42,
+        ))
+        .await
+        .unwrap();
+        assert!(matches!(
+            events.recv().await,
+            Some(ProverEvent::NewTarget(42))
+        ));
+        match events.recv().await {
+            Some(ProverEvent::NewWork(7, challenge, address)) => {
+                assert_eq!(
+                    challenge.to_bytes_le().unwrap(),
+                    epoch_challenge.to_bytes_le().unwrap()
+                );
+                assert_eq!(address, client.address());
+            }
+            _ => panic!("expected new work for epoch 7"),
+        }
+
+        // A solution from another epoch never reaches the pool.
+        for (epoch, solution) in [(6, &[9u8][..]), (7, &[1, 2, 3][..])] {
+            client.outbound().push(Outbound {
+                message: Message::UnconfirmedSolution(UnconfirmedSolution {
+                    puzzle_commitment: testing::commitment(&mut rand::thread_rng()),
+                    solution: Data::Buffer(Bytes::copy_from_slice(solution)),
+                }),
+                epoch: Some(epoch),
+            });
+        }
+        let id = match expect(&mut pool).await {
+            StratumMessage::Submit(id, worker, job_id, solution) => {
+                assert_eq!(worker, "rig");
+                assert_eq!(job_id, "job-1");
+                assert_eq!(solution, "010203");
+                id
+            }
+            other => panic!("expected mining.submit, got {:?}", other),
+        };
+        pool.send(StratumMessage::Response(id, Some(Value::Bool(true)), None))
+            .await
+            .unwrap();
+        assert!(matches!(
+            events.recv().await,
+            Some(ProverEvent::Result(true, None))
+        ));
+
+        drop(pool);
+        assert!(session.await.unwrap().is_err());
+    }
+}
@@ -1,21 +1,20 @@
 use std::{
-    collections::VecDeque,
+    collections::{BTreeMap, VecDeque},
+    path::{Path, PathBuf},
     sync::{
         atomic::{AtomicU32, AtomicU64, Ordering},
         Arc,
     },
-    time::{Duration, SystemTime, UNIX_EPOCH},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use ansi_term::Colour::{Cyan, Green, Red};
-use anyhow::Result;
-use rand::{thread_rng, RngCore};
+use ansi_term::Colour::{Cyan, Green, Red, Yellow};
+use anyhow::{anyhow, Result};
 use rayon::{ThreadPool, ThreadPoolBuilder};
 use serde::{Deserialize, Serialize};
-use snarkos_node_messages::{Data, UnconfirmedSolution};
 use snarkvm::{
     console::account::address::Address,
-    prelude::{CoinbasePuzzle, Testnet3, ToBytes},
+    prelude::{CoinbasePuzzle, Network, ToBytes},
     synthesizer::{EpochChallenge, PuzzleConfig, UniversalSRS},
 };
 
@@ -23,32 +22,48 @@ use snarkvm_algorithms::crypto_hash::sha256d_to_u64;
 
 use tokio::{sync::mpsc, sync::Mutex, task};
 
-use tracing::{debug, error, info, warn};
+use tracing::{debug, info, warn};
 
-use crate::client::Client;
+use crate::{
+    client::Client,
+    engine::{EngineEvent, Job, ProvingEngine},
+    keycache,
+    nonce::{NonceRange, NonceSpace},
+};
+
+/// Proof targets above this would take longer than a prover lives to meet, they can only come
+/// from a broken work source.
+const MAX_PROOF_TARGET: u64 = 1 << 48;
 
-type Message = snarkos_node_messages::Message<Testnet3>;
+/// Epochs whose nonce progress is kept, so going back to one after a failover carries on where
+/// it stopped instead of trying the same nonces again.
+const KEPT_NONCE_EPOCHS: usize = 4;
 
-pub struct Prover {
-    thread_pools: Arc<Vec<Arc<ThreadPool>>>,
-    cuda: Option<Vec<i16>>,
-    _cuda_jobs: Option<u8>,
-    sender: Arc<mpsc::Sender<ProverEvent>>,
+pub struct Prover<N: Network> {
+    engine: ProvingEngine<N>,
+    sender: Arc<mpsc::Sender<ProverEvent<N>>>,
     record_receiver: Arc<Mutex<mpsc::Receiver<Record>>>,
-    client: Arc<Client>,
+    client: Arc<Client<N>>,
     current_epoch: Arc<AtomicU32>,
+    switch_latency: Arc<SwitchLatency>,
+    nonces: NonceSpace,
+    /// Each pool's nonce range, by epoch.
+    nonce_ranges: Arc<std::sync::Mutex<BTreeMap<u32, Vec<Arc<NonceRange>>>>>,
     total_proofs: Arc<AtomicU32>,
     valid_shares: Arc<AtomicU32>,
     invalid_shares: Arc<AtomicU32>,
+    /// Solutions whose outcome nobody could check, counted as neither valid nor invalid.
+    unconfirmed_shares: Arc<AtomicU32>,
     current_proof_target: Arc<AtomicU64>,
-    coinbase_puzzle: CoinbasePuzzle<Testnet3>,
 }
 
 #[allow(clippy::large_enum_variant)]
-pub enum ProverEvent {
+pub enum ProverEvent<N: Network> {
     NewTarget(u64),
-    NewWork(u32, EpochChallenge<Testnet3>, Address<Testnet3>),
-    _Result(bool, Option<String>),
+    NewWork(u32, EpochChallenge<N>, Address<N>),
+    Result(bool, Option<String>),
+    /// A solution left its epoch without anything to tell whether it was accepted.
+    Unconfirmed(String),
 }
 
 #[derive(Serialize, Deserialize)]
@@ -60,78 +75,112 @@ pub struct Record {
     pub timestamp: u128,
 }
 
-impl Prover {
+/// How long pools take to start proving a new epoch, counted from the moment its work arrived.
+///
+/// Switches that abandoned a proof in progress are counted, next to the average proof time
+/// they would otherwise have waited for.
+#[derive(Default)]
+struct SwitchLatency {
+    count: AtomicU64,
+    total_micros: AtomicU64,
+    max_micros: AtomicU64,
+    abandoned: AtomicU64,
+    proofs: AtomicU64,
+    proof_micros: AtomicU64,
+}
+
+/// See [`SwitchLatency::summary`].
+struct SwitchSummary {
+    average_ms: f64,
+    max_ms: f64,
+    switches: u64,
+    abandoned: u64,
+    proof_ms: f64,
+}
+
+impl SwitchLatency {
+    fn record(&self, latency: Duration, abandoned: bool) {
+        let micros = latency.as_micros() as u64;
+        self.count.fetch_add(1, Ordering::SeqCst);
+        self.total_micros.fetch_add(micros, Ordering::SeqCst);
+        self.max_micros.fetch_max(micros, Ordering::SeqCst);
+        if abandoned {
+            self.abandoned.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn record_proof(&self, elapsed: Duration) {
+        self.proofs.fetch_add(1, Ordering::SeqCst);
+        self.proof_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::SeqCst);
+    }
+
+    /// Latencies and proof time in milliseconds, if any switch happened.
+    fn summary(&self) -> Option<SwitchSummary> {
+        let count = self.count.load(Ordering::SeqCst);
+        if count == 0 {
+            return None;
+        }
+        let total = self.total_micros.load(Ordering::SeqCst) as f64 / 1000.0;
+        let proofs = self.proofs.load(Ordering::SeqCst).max(1);
+        Some(SwitchSummary {
+            average_ms: total / count as f64,
+            max_ms: self.max_micros.load(Ordering::SeqCst) as f64 / 1000.0,
+            switches: count,
+            abandoned: self.abandoned.load(Ordering::SeqCst),
+            proof_ms: self.proof_micros.load(Ordering::SeqCst) as f64 / 1000.0 / proofs as f64,
+        })
+    }
+}
+
+impl<N: Network> Prover<N> {
     pub async fn init(
         threads: u16,
         thread_pool_size: u8,
-        client: Arc<Client>,
+        client: Arc<Client<N>>,
         cuda: Option<Vec<i16>>,
         cuda_jobs: Option<u8>,
+        nonces: NonceSpace,
+        key_cache: Option<PathBuf>,
     ) -> Result<Arc<Self>> {
-        let mut thread_pools: Vec<Arc<ThreadPool>> = Vec::new();
-        let pool_count;
-        let pool_threads;
-        if cuda.is_none() {
-            if threads < thread_pool_size as u16 {
-                pool_count = 1;
-                pool_threads = thread_pool_size as u16;
-            } else {
-                pool_count = threads / thread_pool_size as u16;
-                pool_threads = thread_pool_size as u16;
-            }
-        } else {
-            pool_threads = thread_pool_size as u16;
-            pool_count = (cuda_jobs.unwrap_or(1) * cuda.clone().unwrap().len() as u8) as u16;
-        }
-        for index in 0..pool_count {
-            let builder = ThreadPoolBuilder::new()
-                .stack_size(8 * 1024 * 1024)
-                .num_threads(pool_threads as usize);
-            let pool = if cuda.is_none() {
-                builder.thread_name(move |idx| format!("ap-cpu-{}-{}", index, idx))
-            } else {
-                builder.thread_name(move |idx| format!("ap-cuda-{}-{}", index, idx))
-            }
-            .build()?;
-            thread_pools.push(Arc::new(pool));
+        let thread_pools = thread_pools(threads, thread_pool_size, cuda.as_deref(), cuda_jobs)?;
+        if cuda.is_some() {
+            warn!("This version of the prover is only using the first GPU");
         }
         info!(
-            "Created {} prover thread pools with {} threads in each pool",
-            thread_pools.len(),
-            pool_threads
+            "Nonce seed {}, fleet slot {}",
+            nonces.seed(),
+            nonces.fleet()
         );
 
         let (sender, mut receiver) = mpsc::channel(1024);
 
         let (record_sender, record_receiver) = mpsc::channel(1024);
 
-        info!("Initializing universal SRS");
-        let srs = UniversalSRS::<Testnet3>::load().expect("Failed to load SRS");
-        info!("Universal SRS initialized");
-
-        info!("Initializing coinbase proving key");
-        let coinbase_puzzle = CoinbasePuzzle::<Testnet3>::trim(
-            &srs,
-            PuzzleConfig {
-                degree: (1 << 13) - 1,
-            },
-        )
-        .expect("Failed to load coinbase proving key");
-        info!("Coinbase proving key initialized");
+        let coinbase_puzzle = load_coinbase_puzzle::<N>(key_cache.as_deref())?;
+        let current_proof_target = Arc::<AtomicU64>::default();
+        let (event_sender, mut events) = mpsc::unbounded_channel();
+        let engine = ProvingEngine::start(
+            &thread_pools,
+            coinbase_puzzle,
+            current_proof_target.clone(),
+            event_sender,
+        );
 
         let prover = Arc::new(Self {
-            thread_pools: Arc::new(thread_pools),
-            cuda,
-            _cuda_jobs: cuda_jobs,
+            engine,
             sender: Arc::new(sender),
             record_receiver: Arc::new(Mutex::new(record_receiver)),
             client,
             current_epoch: Default::default(),
+            switch_latency: Default::default(),
+            nonces,
+            nonce_ranges: Default::default(),
             total_proofs: Default::default(),
             valid_shares: Default::default(),
             invalid_shares: Default::default(),
-            current_proof_target: Default::default(),
-            coinbase_puzzle,
+            unconfirmed_shares: Default::default(),
+            current_proof_target,
         });
 
         let p = prover.clone();
@@ -144,15 +193,28 @@ impl Prover {
                     ProverEvent::NewWork(epoch_number, epoch_challenge, address) => {
                         p.new_work(epoch_number, epoch_challenge, address).await;
                     }
-                    ProverEvent::_Result(success, error) => {
+                    ProverEvent::Result(success, error) => {
                         p.result(success, error).await;
                     }
+                    ProverEvent::Unconfirmed(msg) => {
+                        p.unconfirmed(msg);
+                    }
                 }
             }
         });
         debug!("Created prover message handler");
 
+        let p = prover.clone();
+        task::spawn(async move {
+            while let Some(event) = events.recv().await {
+                p.engine_event(event);
+            }
+        });
+        debug!("Created proving engine event handler");
+
         let total_proofs = prover.total_proofs.clone();
+        let switch_latency = prover.switch_latency.clone();
+        let nonce_ranges = prover.nonce_ranges.clone();
         task::spawn(async move {
             fn calculate_proof_rate(now: u32, past: u32, interval: u32) -> Box<str> {
                 if interval < 1 {
@@ -186,6 +248,27 @@ impl Prover {
                         calculate_proof_rate(proofs, m60, 60),
                     ))
                 );
+                if let Some(summary) = switch_latency.summary() {
+                    info!(
+                        "Epoch switch-over: {:.1}ms average, {:.1}ms max, {} of {} abandoned a proof in progress ({:.1}ms average proof)",
+                        summary.average_ms,
+                        summary.max_ms,
+                        summary.abandoned,
+                        summary.switches,
+                        summary.proof_ms
+                    );
+                }
+                if let Some((epoch, ranges)) = nonce_ranges.lock().unwrap().iter().next_back() {
+                    for (index, range) in ranges.iter().enumerate() {
+                        debug!(
+                            "Pool {} tried {} nonces of epoch {} ({:.6}% of its range)",
+                            index,
+                            range.tried(),
+                            epoch,
+                            range.progress() * 100.0
+                        );
+                    }
+                }
                 record_sender
                     .send(Record {
                         address: None,
@@ -206,7 +289,7 @@ impl Prover {
         Ok(prover)
     }
 
-    pub fn sender(&self) -> Arc<mpsc::Sender<ProverEvent>> {
+    pub fn sender(&self) -> Arc<mpsc::Sender<ProverEvent<N>>> {
         self.sender.clone()
     }
 
@@ -270,112 +353,263 @@ impl Prover {
         }
     }
 
+    fn unconfirmed(&self, msg: String) {
+        let unconfirmed = self.unconfirmed_shares.fetch_add(1, Ordering::SeqCst) + 1;
+        info!(
+            "{}",
+            Yellow.normal().paint(format!(
+                "Share unconfirmed: {}  ({} unconfirmed)",
+                msg, unconfirmed
+            ))
+        );
+    }
+
     fn new_target(&self, proof_target: u64) {
+        if proof_target == 0 || proof_target > MAX_PROOF_TARGET {
+            warn!("Refusing proof target {}", proof_target);
+            return;
+        }
         self.current_proof_target
             .store(proof_target, Ordering::SeqCst);
         info!("New proof target: {}", proof_target);
     }
 
+    /// The nonce range of every pool for `epoch`, picking up where an earlier visit to the
+    /// same epoch stopped.
+    fn nonce_ranges(&self, epoch: u32) -> Vec<Arc<NonceRange>> {
+        let pools = self.engine.pools() as u32;
+        let mut ranges = self.nonce_ranges.lock().unwrap();
+        let current = ranges
+            .entry(epoch)
+            .or_insert_with(|| {
+                (0..pools)
+                    .map(|pool| Arc::new(self.nonces.range(epoch, pool, pools)))
+                    .collect()
+            })
+            .clone();
+        while ranges.len() > KEPT_NONCE_EPOCHS {
+            let oldest = *ranges.keys().find(|kept| **kept != epoch).unwrap();
+            ranges.remove(&oldest);
+        }
+        current
+    }
+
     async fn new_work(
         &self,
         epoch_number: u32,
-        epoch_challenge: EpochChallenge<Testnet3>,
-        address: Address<Testnet3>,
+        epoch_challenge: EpochChallenge<N>,
+        address: Address<N>,
     ) {
         let last_epoch_number = self.current_epoch.load(Ordering::SeqCst);
-        if epoch_number <= last_epoch_number {
+        if epoch_number == last_epoch_number {
             return;
         }
+        if epoch_number < last_epoch_number {
+            warn!(
+                "Epoch went back from {} to {}",
+                last_epoch_number, epoch_number
+            );
+        }
         self.current_epoch.store(epoch_number, Ordering::SeqCst);
         info!("Received new work: epoch {}", epoch_number);
-        let current_proof_target = self.current_proof_target.clone();
-
-        let current_epoch = self.current_epoch.clone();
-        let client = self.client.clone();
-        let thread_pools = self.thread_pools.clone();
-        let total_proofs = self.total_proofs.clone();
-        let cuda = self.cuda.clone();
-        let coinbase_puzzle = self.coinbase_puzzle.clone();
+        // Proofs already running for the old epoch finish first, they can't be cancelled.
+        self.engine.set_job(Job {
+            epoch_number,
+            epoch_challenge,
+            address,
+            nonces: self.nonce_ranges(epoch_number),
+            received: Instant::now(),
+        });
+    }
 
-        task::spawn(async move {
-            if let Some(_) = cuda {
-                warn!("This version of the prover is only using the first GPU");
+    fn engine_event(&self, event: EngineEvent<N>) {
+        match event {
+            EngineEvent::Switched {
+                pool,
+                epoch_number,
+                latency,
+                abandoned,
+            } => {
+                self.switch_latency.record(latency, abandoned);
+                debug!(
+                    "Pool {} switched to epoch {} in {}ms{}",
+                    pool,
+                    epoch_number,
+                    latency.as_millis(),
+                    if abandoned {
+                        ", abandoning its proof in progress"
+                    } else {
+                        ""
+                    }
+                );
             }
-            for (_, tp) in thread_pools.iter().enumerate() {
-                let current_proof_target = current_proof_target.clone();
-                let current_epoch = current_epoch.clone();
-                let client = client.clone();
-                let epoch_challenge = epoch_challenge.clone();
-                let address = address.clone();
-                let total_proofs = total_proofs.clone();
-                let tp = tp.clone();
-                let coinbase_puzzle = coinbase_puzzle.clone();
-                task::spawn(async move {
-                    loop {
-                        let current_proof_target = current_proof_target.clone();
-                        let epoch_challenge = epoch_challenge.clone();
-                        let address = address.clone();
-                        let tp = tp.clone();
-                        let coinbase_puzzle = coinbase_puzzle.clone();
-                        if epoch_number != current_epoch.load(Ordering::SeqCst) {
-                            debug!(
-                                "Terminating stale work: current {} latest {}",
-                                epoch_number,
-                                current_epoch.load(Ordering::SeqCst)
-                            );
-                            break;
-                        }
-                        let nonce = thread_rng().next_u64();
+            EngineEvent::Proved {
+                epoch_number,
+                nonce,
+                solution,
+                elapsed,
+                ..
+            } => {
+                self.total_proofs.fetch_add(1, Ordering::SeqCst);
+                self.switch_latency.record_proof(elapsed);
+                let solution = match solution {
+                    Some(solution) => solution,
+                    None => {
                         debug!(
-                            "begin to prove epoch {} with nonce {}", epoch_number, nonce
+                            "Solution not found for epoch {} with nonce {}",
+                            epoch_number, nonce
                         );
-                        if let Ok(Ok(solution)) = task::spawn_blocking(move || {
-                            tp.install(|| {
-                                coinbase_puzzle.prove(
-                                    &epoch_challenge,
-                                    address,
-                                    nonce,
-                                    Option::from(current_proof_target.load(Ordering::SeqCst)),
-                                )
-                            })
-                        })
-                        .await
-                        {
-                            if epoch_number != current_epoch.load(Ordering::SeqCst) {
-                                debug!(
-                                    "Terminating stale work: current {} latest {}",
-                                    epoch_number,
-                                    current_epoch.load(Ordering::SeqCst)
-                                );
-                                break;
-                            }
-                            // Ensure the share difficulty target is met.
-                            let proof_difficulty = u64::MAX
-                                / sha256d_to_u64(&*solution.commitment().to_bytes_le().unwrap());
-
-                            info!(
-                                "Solution found for epoch {} with difficulty {}",
-                                epoch_number, proof_difficulty
-                            );
-
-                            // Send a `PoolResponse` to the operator.
-                            let message = Message::UnconfirmedSolution(UnconfirmedSolution {
-                                puzzle_commitment: solution.commitment(),
-                                solution: Data::Object(solution),
-                            });
-                            if let Err(error) = client.sender().send(message).await {
-                                error!("Failed to send Beaconer Response: {}", error);
-                            }
-                            total_proofs.fetch_add(1, Ordering::SeqCst);
-                        } else {
-                            debug!(
-                                "Solution not found for epoch {} with nonce {}", epoch_number, nonce
-                            );
-                            total_proofs.fetch_add(1, Ordering::SeqCst);
-                        }
+                        return;
                     }
-                });
+                };
+                let latest = self.current_epoch.load(Ordering::SeqCst);
+                if epoch_number != latest {
+                    debug!(
+                        "Dropping stale solution: current {} latest {}",
+                        epoch_number, latest
+                    );
+                    return;
+                }
+                // Ensure the share difficulty target is met.
+                let proof_difficulty =
+                    u64::MAX / sha256d_to_u64(&*solution.commitment().to_bytes_le().unwrap());
+
+                info!(
+                    "Solution found for epoch {} with difficulty {}",
+                    epoch_number, proof_difficulty
+                );
+
+                self.client.submit(epoch_number, solution);
             }
-        });
+            EngineEvent::Exhausted { pool, epoch_number } => {
+                warn!(
+                    "Pool {} tried every nonce of its range for epoch {}",
+                    pool, epoch_number
+                );
+            }
+        }
     }
 }
+
+/// Builds the thread pools the proofs run on: one per `thread_pool_size` threads, or
+/// `cuda_jobs` per GPU.
+pub fn thread_pools(
+    threads: u16,
+    thread_pool_size: u8,
+    cuda: Option<&[i16]>,
+    cuda_jobs: Option<u8>,
+) -> Result<Vec<Arc<ThreadPool>>> {
+    let mut thread_pools: Vec<Arc<ThreadPool>> = Vec::new();
+    let pool_count;
+    let pool_threads;
+    if cuda.is_none() {
+        if threads < thread_pool_size as u16 {
+            pool_count = 1;
+            pool_threads = thread_pool_size as u16;
+        } else {
+            pool_count = threads / thread_pool_size as u16;
+            pool_threads = thread_pool_size as u16;
+        }
+    } else {
+        pool_threads = thread_pool_size as u16;
+        pool_count = (cuda_jobs.unwrap_or(1) * cuda.unwrap().len() as u8) as u16;
+    }
+    for index in 0..pool_count {
+        let builder = ThreadPoolBuilder::new()
+            .stack_size(8 * 1024 * 1024)
+            .num_threads(pool_threads as usize);
+        let pool = if cuda.is_none() {
+            builder.thread_name(move |idx| format!("ap-cpu-{}-{}", index, idx))
+        } else {
+            builder.thread_name(move |idx| format!("ap-cuda-{}-{}", index, idx))
+        }
+        .build()?;
+        thread_pools.push(Arc::new(pool));
+    }
+    info!(
+        "Created {} prover thread pools with {} threads in each pool",
+        thread_pools.len(),
+        pool_threads
+    );
+    Ok(thread_pools)
+}
+
+/// Loads the coinbase proving key from `cache` when it holds one for this network and degree
+/// that deserializes. Otherwise loads the SRS, trims the key out of it and writes it to
+/// `cache`. The key is shared by every pool, the SRS is dropped once the key is out.
+///
+/// Logs the resident size taken by each, where the platform tells.
+pub fn load_coinbase_puzzle<N: Network>(cache: Option<&Path>) -> Result<Arc<CoinbasePuzzle<N>>> {
+    const MIB: f64 = 1024.0 * 1024.0;
+    let degree = N::COINBASE_PUZZLE_DEGREE;
+    let before = resident_memory();
+
+    if let Some(path) = cache.filter(|path| path.exists()) {
+        info!("Loading coinbase proving key from {}", path.display());
+        match keycache::read::<N>(path, degree) {
+            Ok(key) => {
+                info!("Coinbase proving key initialized");
+                if let (Some(before), Some(after)) = (before, resident_memory()) {
+                    info!(
+                        "Memory: proving key {:.0} MiB, {:.0} MiB resident",
+                        after.saturating_sub(before) as f64 / MIB,
+                        after as f64 / MIB
+                    );
+                }
+                return Ok(Arc::new(CoinbasePuzzle::Prover(Arc::new(key))));
+            }
+            Err(e) => warn!("Ignoring proving key cache {}: {}", path.display(), e),
+        }
+    }
+
+    info!("Initializing universal SRS");
+    let srs = UniversalSRS::<N>::load().map_err(|e| anyhow!("Failed to load SRS: {}", e))?;
+    info!("Universal SRS initialized");
+    let with_srs = resident_memory();
+
+    info!("Initializing coinbase proving key");
+    let coinbase_puzzle = CoinbasePuzzle::<N>::trim(&srs, PuzzleConfig { degree })
+        .map_err(|e| anyhow!("Failed to load coinbase proving key: {}", e))?;
+    info!("Coinbase proving key initialized");
+    let with_key = resident_memory();
+    drop(srs);
+    let after = resident_memory();
+
+    if let (Some(before), Some(with_srs), Some(with_key), Some(after)) =
+        (before, with_srs, with_key, after)
+    {
+        info!(
+            "Memory: SRS {:.0} MiB, proving key {:.0} MiB, {:.0} MiB resident after dropping the SRS \
+             ({:.0} MiB before loading)",
+            with_srs.saturating_sub(before) as f64 / MIB,
+            with_key.saturating_sub(with_srs) as f64 / MIB,
+            after as f64 / MIB,
+            before as f64 / MIB
+        );
+    }
+
+    if let Some(path) = cache {
+        match coinbase_puzzle
+            .coinbase_proving_key()
+            .and_then(|key| keycache::write::<N>(path, degree, key))
+        {
+            Ok(()) => info!("Cached coinbase proving key in {}", path.display()),
+            Err(e) => warn!("Unable to cache proving key in {}: {}", path.display(), e),
+        }
+    }
+    Ok(Arc::new(coinbase_puzzle))
+}
+
+/// Resident set size of the process in bytes. Only known on Linux.
+fn resident_memory() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let kib = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))?
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+    Some(kib * 1024)
+}
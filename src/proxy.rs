@@ -0,0 +1,380 @@
+use std::{
+    fmt,
+    io::{Error, ErrorKind, Result},
+    net::IpAddr,
+    str::FromStr,
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+/// An egress proxy for outgoing connections.
+///
+/// Parsed from `socks5://[user:pass@]host:port` or `http://[user:pass@]host:port`. Hostnames
+/// are handed to the proxy unresolved, which is what Tor expects.
+#[derive(Clone)]
+pub struct Proxy {
+    kind: Kind,
+    server: String,
+    auth: Option<(String, String)>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Socks5,
+    Http,
+}
+
+impl FromStr for Proxy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (scheme, rest) = s
+            .split_once("://")
+            .ok_or_else(|| format!("missing scheme in proxy {}", s))?;
+        let kind = match scheme {
+            "socks5" | "socks5h" => Kind::Socks5,
+            "http" => Kind::Http,
+            scheme => return Err(format!("unsupported proxy scheme {}", scheme)),
+        };
+        let rest = rest.trim_end_matches('/');
+        let (auth, server) = match rest.rsplit_once('@') {
+            Some((auth, server)) => {
+                let (user, pass) = auth
+                    .split_once(':')
+                    .ok_or_else(|| "proxy credentials must be user:pass".to_string())?;
+                (Some((user.to_string(), pass.to_string())), server)
+            }
+            None => (None, rest),
+        };
+        if server.rsplit_once(':').is_none() {
+            return Err(format!("missing port in proxy {}", s));
+        }
+        Ok(Self {
+            kind,
+            server: server.to_string(),
+            auth,
+        })
+    }
+}
+
+impl fmt::Display for Proxy {
+    /// Never prints the credentials.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let scheme = match self.kind {
+            Kind::Socks5 => "socks5",
+            Kind::Http => "http",
+        };
+        write!(f, "{}://{}", scheme, self.server)
+    }
+}
+
+impl fmt::Debug for Proxy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl Proxy {
+    /// Opens a tunnel through the proxy to `target` (`host:port`).
+    pub async fn connect(&self, target: &str) -> Result<TcpStream> {
+        let mut stream = TcpStream::connect(&self.server).await?;
+        match self.kind {
+            Kind::Socks5 => self.socks5_handshake(&mut stream, target).await?,
+            Kind::Http => self.http_handshake(&mut stream, target).await?,
+        }
+        Ok(stream)
+    }
+
+    /// The same proxy for HTTP clients.
+    pub fn to_reqwest(&self) -> reqwest::Result<reqwest::Proxy> {
+        let scheme = match self.kind {
+            Kind::Socks5 => "socks5h",
+            Kind::Http => "http",
+        };
+        let proxy = reqwest::Proxy::all(format!("{}://{}", scheme, self.server))?;
+        Ok(match &self.auth {
+            Some((user, pass)) => proxy.basic_auth(user, pass),
+            None => proxy,
+        })
+    }
+
+    async fn socks5_handshake(&self, stream: &mut TcpStream, target: &str) -> Result<()> {
+        let method = if self.auth.is_some() { 0x02 } else { 0x00 };
+        stream.write_all(&[0x05, 0x01, method]).await?;
+        let mut reply = [0u8; 2];
+        stream.read_exact(&mut reply).await?;
+        if reply[0] != 0x05 || reply[1] != method {
+            return Err(proxy_error(
+                "SOCKS5 proxy refused the authentication method",
+            ));
+        }
+        if let Some((user, pass)) = &self.auth {
+            let mut request = vec![0x01, user.len() as u8];
+            request.extend_from_slice(user.as_bytes());
+            request.push(pass.len() as u8);
+            request.extend_from_slice(pass.as_bytes());
+            stream.write_all(&request).await?;
+            stream.read_exact(&mut reply).await?;
+            if reply[1] != 0x00 {
+                return Err(proxy_error("SOCKS5 proxy rejected the credentials"));
+            }
+        }
+
+        let (host, port) = split_target(target)?;
+        let mut request = vec![0x05, 0x01, 0x00];
+        match host.parse::<IpAddr>() {
+            Ok(IpAddr::V4(ip)) => {
+                request.push(0x01);
+                request.extend_from_slice(&ip.octets());
+            }
+            Ok(IpAddr::V6(ip)) => {
+                request.push(0x04);
+                request.extend_from_slice(&ip.octets());
+            }
+            Err(_) => {
+                request.push(0x03);
+                request.push(host.len() as u8);
+                request.extend_from_slice(host.as_bytes());
+            }
+        }
+        request.extend_from_slice(&port.to_be_bytes());
+        stream.write_all(&request).await?;
+
+        let mut header = [0u8; 4];
+        stream.read_exact(&mut header).await?;
+        if header[1] != 0x00 {
+            return Err(proxy_error(&format!(
+                "SOCKS5 proxy failed to connect to {} (reply {})",
+                target, header[1]
+            )));
+        }
+        // Skip the bound address, we have no use for it.
+        let address_len = match header[3] {
+            0x01 => 4,
+            0x04 => 16,
+            0x03 => stream.read_u8().await? as usize,
+            atyp => {
+                return Err(proxy_error(&format!(
+                    "unknown SOCKS5 address type {}",
+                    atyp
+                )))
+            }
+        };
+        let mut bound = vec![0u8; address_len + 2];
+        stream.read_exact(&mut bound).await?;
+        Ok(())
+    }
+
+    async fn http_handshake(&self, stream: &mut TcpStream, target: &str) -> Result<()> {
+        let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", target);
+        if let Some((user, pass)) = &self.auth {
+            request.push_str(&format!(
+                "Proxy-Authorization: Basic {}\r\n",
+                base64::encode(format!("{}:{}", user, pass))
+            ));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).await?;
+
+        // Read byte by byte so nothing after the header is consumed from the tunnel.
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\n") {
+            if response.len() > 8192 {
+                return Err(proxy_error("HTTP proxy response header too long"));
+            }
+            response.push(stream.read_u8().await?);
+        }
+        let response = String::from_utf8_lossy(&response);
+        let status = response.lines().next().unwrap_or_default();
+        match status.split_whitespace().nth(1) {
+            Some("200") => Ok(()),
+            _ => Err(proxy_error(&format!(
+                "HTTP proxy refused CONNECT: {}",
+                status
+            ))),
+        }
+    }
+}
+
+fn split_target(target: &str) -> Result<(&str, u16)> {
+    let (host, port) = target
+        .rsplit_once(':')
+        .ok_or_else(|| proxy_error(&format!("missing port in {}", target)))?;
+    let port = port
+        .parse()
+        .map_err(|_| proxy_error(&format!("invalid port in {}", target)))?;
+    Ok((host.trim_start_matches('[').trim_end_matches(']'), port))
+}
+
+fn proxy_error(message: &str) -> Error {
+    Error::new(ErrorKind::Other, message.to_string())
+}
+
+/// Connects to `target` directly, or through `proxy` if one is configured.
+pub async fn connect(proxy: Option<&Proxy>, target: &str) -> Result<TcpStream> {
+    match proxy {
+        Some(proxy) => proxy.connect(target).await,
+        None => TcpStream::connect(target).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{net::TcpListener, task::JoinHandle};
+
+    use super::*;
+
+    /// Starts a stand-in proxy that runs `handshake` on the first connection, then answers
+    /// `ping` with `pong` through the tunnel.
+    async fn stand_in<F, Fut>(scheme: &str, auth: &str, handshake: F) -> (Proxy, JoinHandle<()>)
+    where
+        F: FnOnce(TcpStream) -> Fut + Send + 'static,
+        Fut: std::future::Future<Output = Option<TcpStream>> + Send,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = format!("{}://{}{}", scheme, auth, listener.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            if let Some(mut stream) = handshake(stream).await {
+                let mut ping = [0u8; 4];
+                stream.read_exact(&mut ping).await.unwrap();
+                assert_eq!(&ping, b"ping");
+                stream.write_all(b"pong").await.unwrap();
+            }
+        });
+        (proxy, server)
+    }
+
+    async fn ping(stream: &mut TcpStream) {
+        stream.write_all(b"ping").await.unwrap();
+        let mut pong = [0u8; 4];
+        stream.read_exact(&mut pong).await.unwrap();
+        assert_eq!(&pong, b"pong");
+    }
+
+    async fn read_header(stream: &mut TcpStream) -> String {
+        let mut header = Vec::new();
+        while !header.ends_with(b"\r\n\r\n") {
+            header.push(stream.read_u8().await.unwrap());
+        }
+        String::from_utf8(header).unwrap()
+    }
+
+    #[tokio::test]
+    async fn socks5_without_auth() {
+        let (proxy, server) = stand_in("socks5", "", |mut stream| async move {
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(greeting, [0x05, 0x01, 0x00]);
+            stream.write_all(&[0x05, 0x00]).await.unwrap();
+
+            // The hostname goes to the proxy unresolved.
+            let mut request = [0u8; 5 + 11 + 2];
+            stream.read_exact(&mut request).await.unwrap();
+            assert_eq!(request[..5], [0x05, 0x01, 0x00, 0x03, 11]);
+            assert_eq!(&request[5..16], b"example.org");
+            assert_eq!(request[16..], 4133u16.to_be_bytes());
+            stream
+                .write_all(&[0x05, 0x00, 0x00, 0x03, 4, b'h', b'o', b's', b't', 0, 0])
+                .await
+                .unwrap();
+            Some(stream)
+        })
+        .await;
+
+        let mut stream = proxy.connect("example.org:4133").await.unwrap();
+        ping(&mut stream).await;
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn socks5_with_auth() {
+        let (proxy, server) = stand_in("socks5", "user:pass@", |mut stream| async move {
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(greeting, [0x05, 0x01, 0x02]);
+            stream.write_all(&[0x05, 0x02]).await.unwrap();
+
+            let mut auth = [0u8; 11];
+            stream.read_exact(&mut auth).await.unwrap();
+            assert_eq!(&auth, b"\x01\x04user\x04pass");
+            stream.write_all(&[0x01, 0x00]).await.unwrap();
+
+            let mut request = [0u8; 10];
+            stream.read_exact(&mut request).await.unwrap();
+            assert_eq!(request, [0x05, 0x01, 0x00, 0x01, 10, 0, 0, 1, 0x10, 0x25]);
+            stream
+                .write_all(&[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+            Some(stream)
+        })
+        .await;
+
+        let mut stream = proxy.connect("10.0.0.1:4133").await.unwrap();
+        ping(&mut stream).await;
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn socks5_rejected_credentials() {
+        let (proxy, server) = stand_in("socks5", "user:wrong@", |mut stream| async move {
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting).await.unwrap();
+            stream.write_all(&[0x05, 0x02]).await.unwrap();
+            let mut auth = [0u8; 12];
+            stream.read_exact(&mut auth).await.unwrap();
+            stream.write_all(&[0x01, 0x01]).await.unwrap();
+            None
+        })
+        .await;
+
+        assert!(proxy.connect("example.org:4133").await.is_err());
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn http_connect() {
+        let (proxy, server) = stand_in("http", "user:pass@", |mut stream| async move {
+            let header = read_header(&mut stream).await;
+            assert!(header.starts_with("CONNECT example.org:4133 HTTP/1.1\r\n"));
+            assert!(header.contains("Host: example.org:4133\r\n"));
+            assert!(header.contains(&format!(
+                "Proxy-Authorization: Basic {}\r\n",
+                base64::encode("user:pass")
+            )));
+            stream
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                .await
+                .unwrap();
+            Some(stream)
+        })
+        .await;
+
+        let mut stream = proxy.connect("example.org:4133").await.unwrap();
+        ping(&mut stream).await;
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn http_connect_refused() {
+        let (proxy, server) = stand_in("http", "", |mut stream| async move {
+            let header = read_header(&mut stream).await;
+            assert!(!header.contains("Proxy-Authorization"));
+            stream
+                .write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n")
+                .await
+                .unwrap();
+            None
+        })
+        .await;
+
+        assert!(proxy.connect("example.org:4133").await.is_err());
+        server.await.unwrap();
+    }
+}
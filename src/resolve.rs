@@ -0,0 +1,364 @@
+use std::{
+    io::{Error, ErrorKind},
+    net::SocketAddr,
+    path::Path,
+    pin::Pin,
+    str::FromStr,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{lookup_host, TcpStream},
+    time::timeout,
+};
+use tracing::{debug, warn};
+use trust_dns_resolver::TokioAsyncResolver;
+
+use crate::proxy::{self, Proxy};
+
+/// Prefix of entries naming a Unix domain socket instead of `host:port`.
+const UNIX_PREFIX: &str = "unix:";
+/// Prefix of entries naming an SRV record, e.g. `srv:_aleo._tcp.example.com`.
+const SRV_PREFIX: &str = "srv:";
+
+/// Checks that `entry` looks like `host:port`, `srv:name` or `unix:/path`, without resolving
+/// it. Hostnames and SRV records are resolved again on every connection attempt.
+pub fn validate(entry: &str) -> Result<()> {
+    if let Some(name) = entry.strip_prefix(SRV_PREFIX) {
+        if name.is_empty() || name.contains(':') {
+            return Err(anyhow!("{} is not a valid SRV name", entry));
+        }
+        return Ok(());
+    }
+    if let Some(path) = entry.strip_prefix(UNIX_PREFIX) {
+        if path.is_empty() {
+            return Err(anyhow!("{} is missing a path", entry));
+        }
+        if cfg!(not(unix)) {
+            return Err(anyhow!(
+                "{}: Unix domain sockets are not supported here",
+                entry
+            ));
+        }
+        return Ok(());
+    }
+    let (host, port) = entry
+        .rsplit_once(':')
+        .ok_or_else(|| anyhow!("{} is missing a port", entry))?;
+    if host.is_empty() {
+        return Err(anyhow!("{} is missing a host", entry));
+    }
+    port.parse::<u16>()
+        .map_err(|_| anyhow!("{} has an invalid port", entry))?;
+    Ok(())
+}
+
+/// A connection to a beacon or pool.
+pub enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Connects to `entry`, trying every A/AAAA record of its host until one accepts.
+///
+/// With a proxy the hostname is passed on unresolved and the proxy picks the address. A
+/// `unix:/path` entry connects to that local socket directly, never through the proxy. An
+/// `srv:name` entry tries the targets of its SRV records in order, and is refused with a proxy
+/// since the lookup would go around it.
+pub async fn connect(
+    proxy: Option<&Proxy>,
+    entry: &str,
+    connect_timeout: Duration,
+) -> std::io::Result<Stream> {
+    if let Some(path) = entry.strip_prefix(UNIX_PREFIX) {
+        return connect_unix(path, connect_timeout).await;
+    }
+    if let Some(name) = entry.strip_prefix(SRV_PREFIX) {
+        if proxy.is_some() {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                format!("{}: SRV lookups would bypass the proxy", entry),
+            ));
+        }
+        let targets = lookup_srv(name).await?;
+        debug!("{} resolved to {:?}", entry, targets);
+        let mut last_error = Error::new(ErrorKind::NotFound, format!("{} has no targets", entry));
+        for target in targets {
+            match connect_host(&target, connect_timeout).await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_error = e,
+            }
+        }
+        return Err(last_error);
+    }
+    if proxy.is_some() {
+        return timeout(connect_timeout, proxy::connect(proxy, entry))
+            .await
+            .map_err(|_| Error::new(ErrorKind::TimedOut, "timed out"))?
+            .map(Stream::Tcp);
+    }
+    connect_host(entry, connect_timeout).await
+}
+
+/// Connects to `host:port` directly, trying every A/AAAA record until one accepts.
+async fn connect_host(entry: &str, connect_timeout: Duration) -> std::io::Result<Stream> {
+    let addresses = lookup_host(entry).await?.collect::<Vec<SocketAddr>>();
+    debug!("{} resolved to {:?}", entry, addresses);
+    let mut last_error = Error::new(ErrorKind::NotFound, format!("{} has no addresses", entry));
+    for address in addresses {
+        match timeout(connect_timeout, TcpStream::connect(address)).await {
+            Ok(Ok(socket)) => return Ok(Stream::Tcp(socket)),
+            Ok(Err(e)) => {
+                warn!("Failed to connect to {} ({}): {}", entry, address, e);
+                last_error = e;
+            }
+            Err(_) => {
+                warn!("Failed to connect to {} ({}): Timed out", entry, address);
+                last_error = Error::new(ErrorKind::TimedOut, "timed out");
+            }
+        }
+    }
+    Err(last_error)
+}
+
+/// The targets of the SRV records of `name` as `host:port`, in the order to try them.
+async fn lookup_srv(name: &str) -> std::io::Result<Vec<String>> {
+    let resolver = TokioAsyncResolver::tokio_from_system_conf()
+        .map_err(|e| Error::new(ErrorKind::Other, e.to_string()))?;
+    let lookup = resolver
+        .srv_lookup(name)
+        .await
+        .map_err(|e| Error::new(ErrorKind::NotFound, e.to_string()))?;
+    Ok(srv_order(
+        lookup
+            .iter()
+            .map(|srv| {
+                (
+                    srv.priority(),
+                    srv.weight(),
+                    format!(
+                        "{}:{}",
+                        srv.target().to_utf8().trim_end_matches('.'),
+                        srv.port()
+                    ),
+                )
+            })
+            .collect(),
+    ))
+}
+
+/// Sorts `(priority, weight, target)` records, lowest priority first and the heaviest first
+/// among equal priorities.
+fn srv_order(mut records: Vec<(u16, u16, String)>) -> Vec<String> {
+    records.sort_by_key(|(priority, weight, _)| (*priority, std::cmp::Reverse(*weight)));
+    records.into_iter().map(|(_, _, target)| target).collect()
+}
+
+#[cfg(unix)]
+async fn connect_unix(path: &str, connect_timeout: Duration) -> std::io::Result<Stream> {
+    timeout(connect_timeout, UnixStream::connect(path))
+        .await
+        .map_err(|_| Error::new(ErrorKind::TimedOut, "timed out"))?
+        .map(Stream::Unix)
+}
+
+#[cfg(not(unix))]
+async fn connect_unix(path: &str, _connect_timeout: Duration) -> std::io::Result<Stream> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        format!("{}: Unix domain sockets are not supported here", path),
+    ))
+}
+
+/// A configured beacon: `host:port`, `srv:name` or `unix:/path`, optionally followed by a
+/// selection weight.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BeaconEntry {
+    pub server: String,
+    /// Relative preference among healthy beacons, defaults to 1.
+    pub weight: u32,
+}
+
+impl FromStr for BeaconEntry {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.split_whitespace();
+        let server = parts.next().ok_or_else(|| anyhow!("empty beacon entry"))?;
+        validate(server)?;
+        let weight = match parts.next() {
+            Some(weight) => match weight.parse::<u32>() {
+                Ok(weight) if weight > 0 => weight,
+                _ => return Err(anyhow!("{} has an invalid weight {}", server, weight)),
+            },
+            None => 1,
+        };
+        if let Some(extra) = parts.next() {
+            return Err(anyhow!("unexpected {} after {}", extra, server));
+        }
+        Ok(Self {
+            server: server.to_string(),
+            weight,
+        })
+    }
+}
+
+/// Parses a beacon list: one `host:port [weight]` per line, blank lines and `#` comments are
+/// skipped. Errors name the offending line of `source`.
+pub fn parse_list(text: &str, source: &str) -> Result<Vec<BeaconEntry>> {
+    text.lines()
+        .enumerate()
+        .map(|(number, line)| {
+            (
+                number + 1,
+                line.split('#').next().unwrap_or_default().trim(),
+            )
+        })
+        .filter(|(_, line)| !line.is_empty())
+        .map(|(number, line)| {
+            line.parse::<BeaconEntry>()
+                .map_err(|e| anyhow!("{}:{}: {}", source, number, e))
+        })
+        .collect()
+}
+
+/// Reads a beacon list file, see [`parse_list`].
+pub fn read_list(path: &Path) -> Result<Vec<BeaconEntry>> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| anyhow!("Unable to read {}: {}", path.display(), e))?;
+    parse_list(&text, &path.display().to_string())
+}
+
+/// Loads a seed list from a local file or an `http(s)://` URL, see [`parse_list`].
+pub async fn load_seeds(source: &str, proxy: Option<&Proxy>) -> Result<Vec<BeaconEntry>> {
+    let text = if source.starts_with("http://") || source.starts_with("https://") {
+        let mut builder = reqwest::Client::builder().timeout(Duration::from_secs(30));
+        if let Some(proxy) = proxy {
+            builder = builder.proxy(proxy.to_reqwest()?);
+        }
+        builder
+            .build()?
+            .get(source)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?
+    } else {
+        tokio::fs::read_to_string(source).await?
+    };
+    parse_list(&text, source)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_entries() {
+        for good in [
+            "example.com:4133",
+            "127.0.0.1:4133",
+            "[::1]:4133",
+            "srv:_aleo._tcp.example.com",
+        ] {
+            assert!(validate(good).is_ok(), "{}", good);
+        }
+        for bad in [
+            "example.com",
+            ":4133",
+            "example.com:port",
+            "srv:",
+            "srv:host:4133",
+        ] {
+            assert!(validate(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn srv_targets_by_priority_then_weight() {
+        let records = vec![
+            (20, 0, "backup:4133".to_string()),
+            (10, 1, "light:4133".to_string()),
+            (10, 5, "heavy:4133".to_string()),
+        ];
+        assert_eq!(
+            srv_order(records),
+            ["heavy:4133", "light:4133", "backup:4133"]
+        );
+    }
+
+    #[test]
+    fn parses_weighted_lists() {
+        let entries = parse_list(
+            "# seeds\nexample.com:4133 3\n\nsrv:_aleo._tcp.example.com # fleet\n",
+            "seeds.txt",
+        )
+        .unwrap();
+        assert_eq!(
+            entries,
+            [
+                BeaconEntry {
+                    server: "example.com:4133".to_string(),
+                    weight: 3,
+                },
+                BeaconEntry {
+                    server: "srv:_aleo._tcp.example.com".to_string(),
+                    weight: 1,
+                },
+            ]
+        );
+        let error = parse_list("example.com:4133\nbad\n", "seeds.txt").unwrap_err();
+        assert!(error.to_string().starts_with("seeds.txt:2:"));
+    }
+}
@@ -0,0 +1,370 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::{anyhow, Result};
+use futures::future::BoxFuture;
+use snarkos_node_messages::Data;
+use snarkvm::{
+    prelude::{FromBytes, Network},
+    synthesizer::{Block, EpochChallenge, ProverSolution},
+};
+use tokio::{task, time::sleep};
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    client::{check_work, Client, WorkSource},
+    outbound::Outbound,
+    prover::{Prover, ProverEvent},
+    proxy::Proxy,
+    tracker::SolutionTracker,
+};
+
+type Message<N> = snarkos_node_messages::Message<N>;
+
+/// How often the latest block is polled.
+pub const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A snarkOS-style node REST API.
+///
+/// Uses `GET {base}/{network}/latest/block`, `GET {base}/{network}/block/{height}` and
+/// `POST {base}/{network}/solution/broadcast` with the solution as JSON, so any HTTP server
+/// answering these routes will do, including a local stub.
+pub struct NodeApi<N: Network> {
+    base: String,
+    network: &'static str,
+    http: reqwest::Client,
+    /// Epoch challenge of the latest epoch seen, it only changes once per epoch.
+    challenge: std::sync::Mutex<Option<EpochChallenge<N>>>,
+}
+
+impl<N: Network> NodeApi<N> {
+    pub fn new(base: &str, network: &'static str, proxy: Option<&Proxy>) -> Result<Self> {
+        let mut builder = reqwest::Client::builder().timeout(Duration::from_secs(10));
+        if let Some(proxy) = proxy {
+            builder = builder.proxy(proxy.to_reqwest()?);
+        }
+        Ok(Self {
+            base: base.trim_end_matches('/').to_string(),
+            network,
+            http: builder.build()?,
+            challenge: Default::default(),
+        })
+    }
+
+    pub fn base(&self) -> &str {
+        &self.base
+    }
+
+    async fn get_block(&self, path: &str) -> Result<Block<N>> {
+        Ok(self
+            .http
+            .get(format!("{}/{}/{}", self.base, self.network, path))
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<Block<N>>()
+            .await?)
+    }
+
+    /// Fetches the latest block and the epoch challenge that goes with it.
+    ///
+    /// The challenge is derived the way the ledger does it, from the hash of the block before
+    /// the first block of the epoch.
+    pub async fn latest_work(&self) -> Result<(EpochChallenge<N>, Block<N>)> {
+        let block = self.get_block("latest/block").await?;
+        let (epoch_number, starting_height) = epoch_of::<N>(block.height());
+        let cached = self
+            .challenge
+            .lock()
+            .unwrap()
+            .clone()
+            .filter(|challenge| challenge.epoch_number() == epoch_number);
+        let challenge = match cached {
+            Some(challenge) => challenge,
+            None => {
+                let epoch_block_hash = match starting_height == block.height() {
+                    true => block.previous_hash(),
+                    false => self
+                        .get_block(&format!("block/{}", starting_height))
+                        .await?
+                        .previous_hash(),
+                };
+                let challenge =
+                    EpochChallenge::new(epoch_number, epoch_block_hash, N::COINBASE_PUZZLE_DEGREE)?;
+                *self.challenge.lock().unwrap() = Some(challenge.clone());
+                challenge
+            }
+        };
+        Ok((challenge, block))
+    }
+
+    pub async fn submit(&self, solution: &ProverSolution<N>) -> Result<()> {
+        let response = self
+            .http
+            .post(format!("{}/{}/solution/broadcast", self.base, self.network))
+            .json(solution)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            return Err(anyhow!("{} {}", status, body.trim()));
+        }
+        Ok(())
+    }
+
+    /// Submits a queued `UnconfirmedSolution`, other messages have no REST equivalent.
+    pub async fn submit_message(&self, message: Message<N>) -> Result<()> {
+        let solution = match message {
+            Message::UnconfirmedSolution(message) => match message.solution {
+                Data::Object(solution) => solution,
+                data => data.deserialize().await?,
+            },
+            message => return Err(anyhow!("{} can't be sent over REST", message.name())),
+        };
+        self.submit(&solution).await
+    }
+}
+
+/// The epoch of the block at `height` and the height of the first block of that epoch.
+fn epoch_of<N: Network>(height: u32) -> (u32, u32) {
+    let epoch_number = height / N::NUM_BLOCKS_PER_EPOCH;
+    (epoch_number, epoch_number * N::NUM_BLOCKS_PER_EPOCH)
+}
+
+/// Gets work from and submits solutions to a node REST API, for networks where P2P
+/// connections to beacons are blocked.
+pub struct RestSource {
+    base: String,
+}
+
+impl RestSource {
+    pub fn new(base: String) -> Self {
+        Self { base }
+    }
+}
+
+impl<N: Network> WorkSource<N> for RestSource {
+    fn name(&self) -> &'static str {
+        "rest"
+    }
+
+    fn run(
+        self: Arc<Self>,
+        prover: Arc<Prover<N>>,
+        client: Arc<Client<N>>,
+    ) -> BoxFuture<'static, ()> {
+        Box::pin(async move {
+            let genesis_header = match Block::<N>::from_bytes_le(N::genesis_bytes()) {
+                Ok(genesis) => *genesis.header(),
+                Err(e) => {
+                    error!("Unable to read the genesis block: {}", e);
+                    return;
+                }
+            };
+            let api = match NodeApi::<N>::new(&self.base, client.network(), client.proxy()) {
+                Ok(api) => Arc::new(api),
+                Err(e) => {
+                    error!("Invalid node API {}: {}", self.base, e);
+                    return;
+                }
+            };
+            let tracker = match SolutionTracker::<N>::new(
+                Some(self.base.clone()),
+                client.network(),
+                client.proxy(),
+            ) {
+                Ok(tracker) => Arc::new(tracker),
+                Err(e) => {
+                    error!("Unable to track solutions through {}: {}", self.base, e);
+                    return;
+                }
+            };
+            // Epoch and height of the latest work.
+            let latest = Arc::new(std::sync::Mutex::new(None::<(u32, u32)>));
+
+            let submit_api = api.clone();
+            let submit_tracker = tracker.clone();
+            let submit_latest = latest.clone();
+            let outbound = client.outbound();
+            task::spawn(async move {
+                loop {
+                    let Outbound { message, epoch } = outbound.pop().await;
+                    let (latest_epoch, latest_height) =
+                        (*submit_latest.lock().unwrap()).unwrap_or_default();
+                    if let Some(epoch) = epoch {
+                        if epoch != latest_epoch {
+                            info!(
+                                "Dropping stale solution for epoch {}, current epoch is {}",
+                                epoch, latest_epoch
+                            );
+                            continue;
+                        }
+                    }
+                    let (commitment, epoch) = match (&message, epoch) {
+                        (Message::UnconfirmedSolution(solution), Some(epoch)) => {
+                            (solution.puzzle_commitment, epoch)
+                        }
+                        _ => {
+                            debug!("Nothing to do for {} over REST", message.name());
+                            continue;
+                        }
+                    };
+                    match submit_api.submit_message(message).await {
+                        Ok(()) => {
+                            info!("Submitted solution to {}", submit_api.base());
+                            submit_tracker.track(commitment, epoch, latest_height);
+                        }
+                        Err(e) => warn!("Failed to submit solution: {}", e),
+                    }
+                }
+            });
+
+            let mut latest_height = None;
+            let mut latest_target = None;
+            loop {
+                match api.latest_work().await {
+                    Ok((epoch_challenge, block)) => {
+                        let header = *block.header();
+                        if latest_height != Some(header.height()) {
+                            latest_height = Some(header.height());
+                            debug!("Node is at height {}", header.height());
+                            if let Err(e) = check_work(&genesis_header, &epoch_challenge, &header) {
+                                warn!("{} sent bogus work: {}", self.base, e);
+                                sleep(POLL_INTERVAL).await;
+                                continue;
+                            }
+                            if latest_target != Some(header.proof_target()) {
+                                latest_target = Some(header.proof_target());
+                                if let Err(e) = prover
+                                    .sender()
+                                    .send(ProverEvent::NewTarget(header.proof_target()))
+                                    .await
+                                {
+                                    error!("Error sending new target to prover: {}", e);
+                                }
+                            }
+                            let epoch_number = epoch_challenge.epoch_number();
+                            let previous = latest
+                                .lock()
+                                .unwrap()
+                                .replace((epoch_number, header.height()))
+                                .map(|(epoch, _)| epoch);
+                            if previous != Some(epoch_number) {
+                                info!("Node announced epoch {}", epoch_number);
+                                let tracker = tracker.clone();
+                                let sender = prover.sender();
+                                let height = header.height();
+                                task::spawn(async move {
+                                    tracker.epoch_changed(epoch_number, height, &sender).await;
+                                });
+                                if let Err(e) = prover
+                                    .sender()
+                                    .send(ProverEvent::NewWork(
+                                        epoch_number,
+                                        epoch_challenge,
+                                        client.address(),
+                                    ))
+                                    .await
+                                {
+                                    error!("Error sending new work to prover: {}", e);
+                                }
+                            }
+                        }
+                    }
+                    Err(e) => warn!("Failed to get work from {}: {}", self.base, e),
+                }
+                sleep(POLL_INTERVAL).await;
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use snarkvm::prelude::Testnet3;
+
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn epoch_of_height() {
+        let blocks = Testnet3::NUM_BLOCKS_PER_EPOCH;
+        assert_eq!(epoch_of::<Testnet3>(0), (0, 0));
+        assert_eq!(epoch_of::<Testnet3>(blocks - 1), (0, 0));
+        assert_eq!(epoch_of::<Testnet3>(blocks), (1, blocks));
+        assert_eq!(epoch_of::<Testnet3>(3 * blocks + 7), (3, 3 * blocks));
+    }
+
+    #[tokio::test]
+    async fn latest_work_derives_the_epoch_challenge() {
+        let genesis = Block::<Testnet3>::from_bytes_le(Testnet3::genesis_bytes()).unwrap();
+        let (base, mut requests) = testing::http_stub(HashMap::from([(
+            "/testnet3/latest/block".to_string(),
+            serde_json::to_string(&genesis).unwrap(),
+        )]))
+        .await;
+        let api = NodeApi::<Testnet3>::new(&base, "testnet3", None).unwrap();
+
+        let (challenge, block) = api.latest_work().await.unwrap();
+        assert_eq!(block.hash(), genesis.hash());
+        // The genesis block starts epoch 0, its challenge comes from the hash before it.
+        let expected = EpochChallenge::<Testnet3>::new(
+            0,
+            genesis.previous_hash(),
+            Testnet3::COINBASE_PUZZLE_DEGREE,
+        )
+        .unwrap();
+        assert_eq!(challenge.epoch_number(), 0);
+        assert_eq!(challenge.epoch_block_hash(), expected.epoch_block_hash());
+        assert_eq!(
+            requests.recv().await.unwrap().path,
+            "/testnet3/latest/block"
+        );
+
+        // The challenge is cached for the rest of the epoch.
+        let (again, _) = api.latest_work().await.unwrap();
+        assert_eq!(again.epoch_block_hash(), expected.epoch_block_hash());
+        assert_eq!(
+            requests.recv().await.unwrap().path,
+            "/testnet3/latest/block"
+        );
+        assert!(requests.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn latest_work_fails_without_a_block() {
+        let (base, _) = testing::http_stub(HashMap::new()).await;
+        let api = NodeApi::<Testnet3>::new(&base, "testnet3", None).unwrap();
+        assert!(api.latest_work().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn submit_posts_the_solution() {
+        let solution = testing::solution(&mut rand::thread_rng());
+        let (base, mut requests) = testing::http_stub(HashMap::from([(
+            "/testnet3/solution/broadcast".to_string(),
+            "\"ok\"".to_string(),
+        )]))
+        .await;
+        let api = NodeApi::<Testnet3>::new(&base, "testnet3", None).unwrap();
+
+        api.submit(&solution).await.unwrap();
+        let request = requests.recv().await.unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/testnet3/solution/broadcast");
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&request.body).unwrap(),
+            serde_json::to_value(&solution).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn submit_reports_refusals() {
+        let (base, _) = testing::http_stub(HashMap::new()).await;
+        let api = NodeApi::<Testnet3>::new(&base, "testnet3", None).unwrap();
+        let solution = testing::solution(&mut rand::thread_rng());
+        assert!(api.submit(&solution).await.is_err());
+    }
+}
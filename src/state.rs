@@ -0,0 +1,162 @@
+use std::fmt;
+
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+/// Lifecycle of a single beacon connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// Opening the TCP connection.
+    Connecting,
+    /// Connected, exchanging `ChallengeRequest`/`ChallengeResponse`/`Ping`/`Pong`.
+    Handshaking,
+    /// Handshake complete, the connection carries work and solutions.
+    Ready,
+    /// Not connected, waiting before the next attempt.
+    Backoff,
+}
+
+impl fmt::Display for ConnectionState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ConnectionState::Connecting => "connecting",
+            ConnectionState::Handshaking => "handshaking",
+            ConnectionState::Ready => "ready",
+            ConnectionState::Backoff => "backoff",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionEvent {
+    /// A connection attempt starts.
+    Connect,
+    /// The TCP connection is established.
+    Connected,
+    /// The peer signed our challenge and then answered our first `Ping`.
+    HandshakeComplete,
+    /// The session ended, for whatever reason.
+    Closed,
+}
+
+impl ConnectionState {
+    /// Returns the state after `event`, or `None` if `event` is not valid in this state.
+    pub fn next(self, event: ConnectionEvent) -> Option<ConnectionState> {
+        use ConnectionEvent::*;
+        use ConnectionState::*;
+        match (self, event) {
+            (Backoff, Connect) => Some(Connecting),
+            (Connecting, Connected) => Some(Handshaking),
+            (Handshaking, HandshakeComplete) => Some(Ready),
+            (Connecting | Handshaking | Ready, Closed) => Some(Backoff),
+            _ => None,
+        }
+    }
+}
+
+/// Drives a [`ConnectionState`] and publishes every transition to observers.
+pub struct ConnectionMachine {
+    index: usize,
+    state: watch::Sender<ConnectionState>,
+}
+
+impl ConnectionMachine {
+    pub fn new(index: usize) -> Self {
+        let (state, _) = watch::channel(ConnectionState::Backoff);
+        Self { index, state }
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        *self.state.borrow()
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<ConnectionState> {
+        self.state.subscribe()
+    }
+
+    /// Applies `event`, returning whether the transition was valid. Invalid events leave the
+    /// state untouched.
+    pub fn apply(&self, event: ConnectionEvent) -> bool {
+        let current = self.state();
+        match current.next(event) {
+            Some(next) => {
+                info!("#{} {} -> {}", self.index, current, next);
+                self.state.send_replace(next);
+                true
+            }
+            None => {
+                warn!("#{} ignoring {:?} while {}", self.index, event, current);
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STATES: [ConnectionState; 4] = [
+        ConnectionState::Connecting,
+        ConnectionState::Handshaking,
+        ConnectionState::Ready,
+        ConnectionState::Backoff,
+    ];
+    const EVENTS: [ConnectionEvent; 4] = [
+        ConnectionEvent::Connect,
+        ConnectionEvent::Connected,
+        ConnectionEvent::HandshakeComplete,
+        ConnectionEvent::Closed,
+    ];
+
+    #[test]
+    fn transitions() {
+        use ConnectionEvent::*;
+        use ConnectionState::*;
+        let valid = [
+            (Backoff, Connect, Connecting),
+            (Connecting, Connected, Handshaking),
+            (Handshaking, HandshakeComplete, Ready),
+            (Connecting, Closed, Backoff),
+            (Handshaking, Closed, Backoff),
+            (Ready, Closed, Backoff),
+        ];
+        for state in STATES {
+            for event in EVENTS {
+                let expected = valid
+                    .iter()
+                    .find(|(from, on, _)| *from == state && *on == event)
+                    .map(|(_, _, to)| *to);
+                assert_eq!(state.next(event), expected, "{:?} on {:?}", event, state);
+            }
+        }
+    }
+
+    #[test]
+    fn apply_publishes_transitions() {
+        let machine = ConnectionMachine::new(0);
+        let mut subscriber = machine.subscribe();
+        assert_eq!(*subscriber.borrow_and_update(), ConnectionState::Backoff);
+
+        assert!(machine.apply(ConnectionEvent::Connect));
+        assert!(subscriber.has_changed().unwrap());
+        assert_eq!(*subscriber.borrow_and_update(), ConnectionState::Connecting);
+
+        assert!(machine.apply(ConnectionEvent::Connected));
+        assert!(machine.apply(ConnectionEvent::HandshakeComplete));
+        assert_eq!(*subscriber.borrow_and_update(), ConnectionState::Ready);
+        assert_eq!(machine.state(), ConnectionState::Ready);
+    }
+
+    #[test]
+    fn apply_ignores_invalid_events() {
+        let machine = ConnectionMachine::new(0);
+        let mut subscriber = machine.subscribe();
+        subscriber.borrow_and_update();
+
+        assert!(!machine.apply(ConnectionEvent::HandshakeComplete));
+        assert!(!subscriber.has_changed().unwrap());
+        assert_eq!(machine.state(), ConnectionState::Backoff);
+    }
+}
@@ -0,0 +1,114 @@
+use std::{collections::HashMap, sync::Arc};
+
+use rand::Rng;
+use snarkos_account::Account;
+use snarkvm::{
+    console::account::address::Address,
+    curves::bls12_377::Bls12_377,
+    prelude::{Network, Testnet3},
+    synthesizer::{EpochChallenge, PartialSolution, ProverSolution, PuzzleCommitment},
+};
+use snarkvm_algorithms::polycommit::kzg10::{KZGCommitment, KZGProof};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+    sync::mpsc,
+};
+
+/// A random puzzle commitment, for tests that only compare commitments.
+pub fn commitment(rng: &mut impl Rng) -> PuzzleCommitment<Testnet3> {
+    PuzzleCommitment::new(KZGCommitment::<Bls12_377>(rng.gen()))
+}
+
+/// A solution with a random commitment and proof, it would never verify.
+pub fn solution(rng: &mut impl Rng) -> ProverSolution<Testnet3> {
+    let partial_solution = PartialSolution::new(address(), rng.gen(), commitment(rng));
+    ProverSolution::new(
+        partial_solution,
+        KZGProof::<Bls12_377> {
+            w: rng.gen(),
+            random_v: None,
+        },
+    )
+}
+
+/// An address nobody keeps the key of.
+pub fn address() -> Address<Testnet3> {
+    Account::<Testnet3>::new(&mut rand::thread_rng())
+        .unwrap()
+        .address()
+}
+
+/// The challenge of `epoch` on top of the default block hash.
+pub fn epoch_challenge(epoch: u32) -> EpochChallenge<Testnet3> {
+    EpochChallenge::new(epoch, Default::default(), Testnet3::COINBASE_PUZZLE_DEGREE).unwrap()
+}
+
+/// A request received by [`http_stub`].
+#[derive(Debug)]
+pub struct StubRequest {
+    pub method: String,
+    pub path: String,
+    pub body: String,
+}
+
+/// Serves `routes`, path to JSON body, on a local port for the rest of the test, anything else
+/// gets a 404. Returns the base URL and every request received.
+pub async fn http_stub(
+    routes: HashMap<String, String>,
+) -> (String, mpsc::UnboundedReceiver<StubRequest>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let base = format!("http://{}", listener.local_addr().unwrap());
+    let routes = Arc::new(routes);
+    let (sender, requests) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let routes = routes.clone();
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                let mut header = Vec::new();
+                while !header.ends_with(b"\r\n\r\n") {
+                    match stream.read_u8().await {
+                        Ok(byte) => header.push(byte),
+                        Err(_) => return,
+                    }
+                }
+                let header = String::from_utf8_lossy(&header).to_string();
+                let mut request_line = header.lines().next().unwrap_or_default().split(' ');
+                let method = request_line.next().unwrap_or_default().to_string();
+                let path = request_line.next().unwrap_or_default().to_string();
+                let length = header
+                    .lines()
+                    .filter_map(|line| line.split_once(':'))
+                    .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+                    .and_then(|(_, value)| value.trim().parse().ok())
+                    .unwrap_or(0);
+                let mut body = vec![0u8; length];
+                if stream.read_exact(&mut body).await.is_err() {
+                    return;
+                }
+                let (status, response) = match routes.get(&path) {
+                    Some(response) => ("200 OK", response.as_str()),
+                    None => ("404 Not Found", "{}"),
+                };
+                let _ = sender.send(StubRequest {
+                    method,
+                    path,
+                    body: String::from_utf8_lossy(&body).to_string(),
+                });
+                let _ = stream
+                    .write_all(
+                        format!(
+                            "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                            status,
+                            response.len(),
+                            response
+                        )
+                        .as_bytes(),
+                    )
+                    .await;
+            });
+        }
+    });
+    (base, requests)
+}
@@ -0,0 +1,256 @@
+use std::{sync::Mutex, time::Duration};
+
+use anyhow::Result;
+use serde_json::Value;
+use snarkvm::{prelude::Network, synthesizer::PuzzleCommitment};
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+
+use crate::{prover::ProverEvent, proxy::Proxy};
+
+/// Blocks scanned at most when looking for our solutions after an epoch ends.
+const MAX_SCANNED_BLOCKS: u32 = 64;
+
+struct Pending<N: Network> {
+    commitment: PuzzleCommitment<N>,
+    epoch: u32,
+    /// Latest block height known when the solution was sent.
+    height: u32,
+}
+
+/// Follows submitted solutions until we know what became of them.
+///
+/// A solution is accepted once a beacon gossips it back to us or it shows up in a block served
+/// by the node API. Solutions still unresolved when their epoch ends are counted as stale.
+pub struct SolutionTracker<N: Network> {
+    pending: Mutex<Vec<Pending<N>>>,
+    node_api: Option<String>,
+    network: &'static str,
+    http: reqwest::Client,
+}
+
+impl<N: Network> SolutionTracker<N> {
+    /// Fails if `proxy` can't be used for the node API, rather than scanning blocks around it.
+    pub fn new(
+        node_api: Option<String>,
+        network: &'static str,
+        proxy: Option<&Proxy>,
+    ) -> Result<Self> {
+        let mut builder = reqwest::Client::builder().timeout(Duration::from_secs(10));
+        if let Some(proxy) = proxy {
+            builder = builder.proxy(proxy.to_reqwest()?);
+        }
+        Ok(Self {
+            pending: Default::default(),
+            node_api: node_api.map(|api| api.trim_end_matches('/').to_string()),
+            network,
+            http: builder.build()?,
+        })
+    }
+
+    pub fn track(&self, commitment: PuzzleCommitment<N>, epoch: u32, height: u32) {
+        let mut pending = self.pending.lock().unwrap();
+        if !pending.iter().any(|p| p.commitment == commitment) {
+            pending.push(Pending {
+                commitment,
+                epoch,
+                height,
+            });
+        }
+    }
+
+    /// Marks `commitment` as accepted if it is one of ours, returning whether it was.
+    pub fn confirm(&self, commitment: &PuzzleCommitment<N>) -> bool {
+        let mut pending = self.pending.lock().unwrap();
+        match pending.iter().position(|p| p.commitment == *commitment) {
+            Some(index) => {
+                pending.swap_remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Resolves every solution from an epoch other than `epoch`, reporting the outcomes to the
+    /// prover.
+    pub async fn epoch_changed(
+        &self,
+        epoch: u32,
+        height: u32,
+        prover: &mpsc::Sender<ProverEvent<N>>,
+    ) {
+        let stale: Vec<Pending<N>> = {
+            let mut pending = self.pending.lock().unwrap();
+            let (stale, current): (Vec<_>, Vec<_>) =
+                pending.drain(..).partition(|p| p.epoch != epoch);
+            *pending = current;
+            stale
+        };
+        if stale.is_empty() {
+            return;
+        }
+
+        let mut included = Vec::new();
+        // Without a node API, or with a block missing, a solution that wasn't found may still
+        // have been included, so it is reported as unconfirmed rather than stale.
+        let mut checked = self.node_api.is_some();
+        if let Some(api) = &self.node_api {
+            let from = stale.iter().map(|p| p.height).min().unwrap_or(height) + 1;
+            let from = from.max(height.saturating_sub(MAX_SCANNED_BLOCKS - 1));
+            for block_height in from..=height {
+                match self.block_commitments(api, block_height).await {
+                    Ok(commitments) => {
+                        included.extend(commitments.into_iter().map(|c| (c, block_height)));
+                    }
+                    Err(e) => {
+                        warn!(
+                            "Failed to fetch block {} from node API: {}",
+                            block_height, e
+                        );
+                        checked = false;
+                    }
+                }
+            }
+        }
+
+        for pending in stale {
+            let event = match included.iter().find(|(c, _)| *c == pending.commitment) {
+                Some((_, block_height)) => {
+                    ProverEvent::Result(true, Some(format!("included in block {}", block_height)))
+                }
+                None if checked => ProverEvent::Result(
+                    false,
+                    Some(format!("stale, epoch {} ended", pending.epoch)),
+                ),
+                None if self.node_api.is_none() => ProverEvent::Unconfirmed(format!(
+                    "epoch {} ended, no node API to check it",
+                    pending.epoch
+                )),
+                None => ProverEvent::Unconfirmed(format!(
+                    "epoch {} ended, some of its blocks could not be fetched",
+                    pending.epoch
+                )),
+            };
+            if let Err(e) = prover.send(event).await {
+                warn!("Error sending share result to prover: {}", e);
+            }
+        }
+    }
+
+    /// The commitments of the solutions in block `height`.
+    ///
+    /// Only `coinbase.partial_solutions[].commitment` is read, the rest of the block is never
+    /// deserialized or checked.
+    async fn block_commitments(&self, api: &str, height: u32) -> Result<Vec<PuzzleCommitment<N>>> {
+        let block = self
+            .http
+            .get(format!("{}/{}/block/{}", api, self.network, height))
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<Value>()
+            .await?;
+        let commitments = match block["coinbase"]["partial_solutions"].as_array() {
+            Some(solutions) => solutions
+                .iter()
+                .map(|solution| serde_json::from_value(solution["commitment"].clone()))
+                .collect::<Result<Vec<_>, _>>()?,
+            None => Vec::new(),
+        };
+        debug!("Block {} has {} solutions", height, commitments.len());
+        Ok(commitments)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde_json::json;
+    use snarkvm::prelude::Testnet3;
+
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn confirm_matches_only_our_commitments() {
+        let mut rng = rand::thread_rng();
+        let ours = testing::commitment(&mut rng);
+        let theirs = testing::commitment(&mut rng);
+        let tracker = SolutionTracker::<Testnet3>::new(None, "testnet3", None).unwrap();
+        tracker.track(ours, 1, 10);
+
+        assert!(!tracker.confirm(&theirs));
+        assert!(tracker.confirm(&ours));
+        // Each solution is only accepted once.
+        assert!(!tracker.confirm(&ours));
+    }
+
+    #[tokio::test]
+    async fn epoch_changed_reports_accepted_and_stale() {
+        let mut rng = rand::thread_rng();
+        let included = testing::commitment(&mut rng);
+        let missed = testing::commitment(&mut rng);
+        let current = testing::commitment(&mut rng);
+        let (api, _) = testing::http_stub(HashMap::from([
+            (
+                "/testnet3/block/11".to_string(),
+                json!({
+                    "coinbase": {
+                        "partial_solutions": [{ "commitment": serde_json::to_value(&included).unwrap() }]
+                    }
+                })
+                .to_string(),
+            ),
+            (
+                "/testnet3/block/12".to_string(),
+                json!({ "coinbase": null }).to_string(),
+            ),
+        ]))
+        .await;
+        let tracker = SolutionTracker::<Testnet3>::new(Some(api), "testnet3", None).unwrap();
+        tracker.track(included, 1, 10);
+        tracker.track(missed, 1, 10);
+        tracker.track(current, 2, 11);
+
+        let (sender, mut events) = mpsc::channel(16);
+        tracker.epoch_changed(2, 12, &sender).await;
+        drop(sender);
+
+        match events.recv().await {
+            Some(ProverEvent::Result(true, Some(message))) => {
+                assert_eq!(message, "included in block 11")
+            }
+            _ => panic!("expected the included solution to be accepted"),
+        }
+        match events.recv().await {
+            Some(ProverEvent::Result(false, Some(message))) => {
+                assert_eq!(message, "stale, epoch 1 ended")
+            }
+            _ => panic!("expected the missed solution to be stale"),
+        }
+        assert!(events.recv().await.is_none());
+        // Solutions of the current epoch stay pending.
+        assert!(tracker.confirm(&current));
+    }
+
+    #[tokio::test]
+    async fn epoch_changed_without_node_api_reports_unconfirmed() {
+        let mut rng = rand::thread_rng();
+        let ended = testing::commitment(&mut rng);
+        let tracker = SolutionTracker::<Testnet3>::new(None, "testnet3", None).unwrap();
+        tracker.track(ended, 1, 10);
+
+        let (sender, mut events) = mpsc::channel(16);
+        tracker.epoch_changed(2, 12, &sender).await;
+        drop(sender);
+
+        match events.recv().await {
+            Some(ProverEvent::Unconfirmed(message)) => {
+                assert_eq!(message, "epoch 1 ended, no node API to check it")
+            }
+            _ => panic!("expected the solution to be unconfirmed, not rejected"),
+        }
+        assert!(events.recv().await.is_none());
+    }
+}
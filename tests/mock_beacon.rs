@@ -0,0 +1,106 @@
+//! Runs the prover against the mock beacon and follows it through an epoch change.
+
+use std::{
+    io::{BufRead, BufReader},
+    net::TcpListener,
+    process::{Child, Command, Stdio},
+    sync::mpsc::{self, Receiver},
+    thread,
+    time::{Duration, Instant},
+};
+
+use rand::rngs::OsRng;
+use snarkos_account::Account;
+use snarkvm::{
+    prelude::{FromBytes, Network, Testnet3},
+    synthesizer::Block,
+};
+
+/// Kills the child when the test ends, passed or not.
+struct Kill(Child);
+
+impl Drop for Kill {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// Waits for a line containing `needle`, failing the test after `timeout`.
+fn wait_for(lines: &Receiver<String>, needle: &str, timeout: Duration) {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        match lines.recv_timeout(left) {
+            Ok(line) if line.contains(needle) => return,
+            Ok(_) => {}
+            Err(e) => panic!("no \"{}\" from the prover: {}", needle, e),
+        }
+    }
+}
+
+#[test]
+fn prover_follows_mock_beacon_epochs() {
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let address = Account::<Testnet3>::new(&mut OsRng)
+        .unwrap()
+        .address()
+        .to_string();
+
+    let mut prover = Kill(
+        Command::new(env!("CARGO_BIN_EXE_aleo-prover"))
+            .args(["--address", &address])
+            .args(["--beacon", &format!("127.0.0.1:{}", port)])
+            .args(["-t", "1", "-i", "1", "--no-key-cache"])
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap(),
+    );
+    let (sender, lines) = mpsc::channel();
+    let stdout = BufReader::new(prover.0.stdout.take().unwrap());
+    thread::spawn(move || {
+        for line in stdout.lines().map_while(Result::ok) {
+            if sender.send(line).is_err() {
+                break;
+            }
+        }
+    });
+
+    // Loading the SRS takes a while, the epoch schedule only starts once the prover is ready
+    // to connect. Its first attempt may beat the mock, the next one comes 5s later.
+    wait_for(
+        &lines,
+        "Coinbase proving key initialized",
+        Duration::from_secs(600),
+    );
+    // The prover refuses work whose proof target is above the header's coinbase target, the
+    // genesis proof target is one it takes.
+    let proof_target = Block::<Testnet3>::from_bytes_le(Testnet3::genesis_bytes())
+        .unwrap()
+        .header()
+        .proof_target();
+    let _mock = Kill(
+        Command::new(env!("CARGO_BIN_EXE_mock-beacon"))
+            .args(["--listen", &format!("127.0.0.1:{}", port)])
+            .args(["--step", &format!("1 {} 30", proof_target)])
+            .args(["--step", &format!("2 {} 3600", proof_target)])
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap(),
+    );
+
+    wait_for(
+        &lines,
+        "Received new work: epoch 1",
+        Duration::from_secs(30),
+    );
+    wait_for(
+        &lines,
+        "Received new work: epoch 2",
+        Duration::from_secs(60),
+    );
+}